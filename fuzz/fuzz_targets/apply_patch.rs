@@ -0,0 +1,19 @@
+//! Applies arbitrary patches to a fixed source. The checksums are filled in, otherwise almost
+//! every input would be rejected before being decoded.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use translator::patch;
+
+const SOURCE: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+fuzz_target!(|data: &[u8]| {
+    let mut patch = data.to_vec();
+    patch.extend_from_slice(&crc32fast::hash(SOURCE).to_le_bytes());
+    patch.extend_from_slice(&0u32.to_le_bytes());
+    let patch_crc = crc32fast::hash(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+
+    let _ = patch::apply(SOURCE, &patch);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use translator::SectionFilter;
+
+fuzz_target!(|data: &[u8]| {
+    let mut warnings = Vec::new();
+    let _ = translator::extract_strings(data, None, &SectionFilter::InitializedData, 3, &mut warnings);
+});
@@ -0,0 +1,49 @@
+//! Translates arbitrary files with every option that changes the layout of PE files. The first
+//! byte picks the options, the rest is the executable.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use translator::{ChecksumPolicy, Options, OverflowPolicy, Patcher, SectionFilter, Translation, TranslationSet};
+
+fn translations() -> TranslationSet {
+    let pairs = [
+        ("Open", "Ouvrir"),
+        ("Quit", "Q"),
+        ("Hello world", "Bonjour tout le monde"),
+        ("&File", "&Fichier"),
+    ];
+
+    TranslationSet::new(pairs.iter()
+        .map(|&(original, translated)| Translation {
+            original: original.to_string(),
+            translated: translated.to_string(),
+            encoding: None,
+            section: None,
+        })
+        .collect())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (&flags, exe_buf) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    let options = Options {
+        sections: if flags & 0x1 != 0 { SectionFilter::InitializedData } else { SectionFilter::ReadOnlyData },
+        overflow_policy: match flags >> 1 & 0x3 {
+            0 => OverflowPolicy::Skip,
+            1 => OverflowPolicy::Overwrite,
+            _ => OverflowPolicy::Relocate,
+        },
+        resources: flags & 0x8 != 0,
+        dotnet: flags & 0x10 != 0,
+        strip_signature: flags & 0x20 != 0,
+        checksum: if flags & 0x40 != 0 { ChecksumPolicy::Always } else { ChecksumPolicy::Never },
+        ..Options::default()
+    };
+
+    let mut patcher = Patcher::new(exe_buf.to_vec());
+    let _ = patcher.translate(&translations(), &options);
+});
@@ -0,0 +1,103 @@
+//! Walks the IL code of method bodies to find the `ldstr` instructions.
+
+use std::collections::HashMap;
+
+use crate::pe;
+
+const LDSTR: u8 = 0x72;
+const SWITCH: u8 = 0x45;
+/// First byte of the two byte opcodes
+const PREFIX: u8 = 0xfe;
+
+const FORMAT_MASK: u8 = 0x3;
+const TINY_FORMAT: u8 = 0x2;
+const FAT_FORMAT: u8 = 0x3;
+
+/// Size of the operand of a one byte opcode, or None if it isn't a valid opcode. `switch` is
+/// followed by a count of 4 byte targets and is handled apart.
+fn operand_size(opcode: u8) -> Option<usize> {
+    match opcode {
+        0x00 ..= 0x0d | 0x14 ..= 0x1e | 0x25 | 0x26 | 0x2a | 0x46 ..= 0x6e | 0x76 | 0x7a | 0x82 ..= 0x8b
+            | 0x8e | 0x90 ..= 0xa2 | 0xb3 ..= 0xba | 0xc3 | 0xd1 ..= 0xdc | 0xdf | 0xe0 => Some(0),
+        0x0e ..= 0x13 | 0x1f | 0x2b ..= 0x37 | 0xde => Some(1),
+        0x20 | 0x22 | 0x27 ..= 0x29 | 0x38 ..= 0x44 | 0x6f ..= 0x75 | 0x79 | 0x7b ..= 0x81 | 0x8c | 0x8d
+            | 0x8f | 0xa3 ..= 0xa5 | 0xc2 | 0xc6 | 0xd0 | 0xdd => Some(4),
+        0x21 | 0x23 => Some(8),
+        _ => None,
+    }
+}
+
+/// Size of the operand of a two byte opcode, given its second byte
+fn prefixed_operand_size(opcode: u8) -> Option<usize> {
+    match opcode {
+        0x00 ..= 0x05 | 0x0f | 0x11 | 0x13 | 0x14 | 0x17 | 0x18 | 0x1a | 0x1d | 0x1e => Some(0),
+        0x12 | 0x19 => Some(1),
+        0x09 ..= 0x0e => Some(2),
+        0x06 | 0x07 | 0x15 | 0x16 | 0x1c => Some(4),
+        _ => None,
+    }
+}
+
+/// Returns the offset and size of the code of the method body at `offset`, skipping its tiny
+/// or fat header.
+fn code(buf: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let first = *buf.get(offset)?;
+
+    match first & FORMAT_MASK {
+        TINY_FORMAT => Some((offset + 1, (first >> 2) as usize)),
+        FAT_FORMAT => {
+            buf.get(offset .. offset + 12)?;
+            let header_size = (pe::read_u16(buf, offset) >> 12) as usize * 4;
+            Some((offset + header_size, pe::read_u32(buf, offset + 4) as usize))
+        },
+        _ => None,
+    }
+}
+
+/// Rewrites the `ldstr` tokens of the method body at `offset` that are keys of `tokens`.
+/// Returns the number of tokens rewritten, or None if the body can't be decoded, in which
+/// case it is left as is.
+pub fn rewrite_ldstr(buf: &mut [u8], offset: usize, tokens: &HashMap<u32, u32>) -> Option<usize> {
+    let (start, size) = code(buf, offset)?;
+    let end = start.checked_add(size)?;
+    buf.get(start .. end)?;
+
+    // Tokens are only written once the whole body was decoded
+    let mut found = Vec::new();
+    let mut i = start;
+    while i < end {
+        let opcode = buf[i];
+        i += 1;
+
+        let operand = match opcode {
+            PREFIX => {
+                let opcode = *buf.get(i)?;
+                i += 1;
+                prefixed_operand_size(opcode)?
+            },
+            SWITCH => {
+                buf.get(i .. i + 4)?;
+                4 + pe::read_u32(buf, i) as usize * 4
+            },
+            _ => operand_size(opcode)?,
+        };
+
+        if opcode == LDSTR && i + 4 <= end {
+            if let Some(&token) = tokens.get(&pe::read_u32(buf, i)) {
+                found.push((i, token));
+            }
+        }
+
+        i = i.checked_add(operand)?;
+    }
+
+    if i != end {
+        return None;
+    }
+
+    for &(offset, token) in found.iter() {
+        pe::write_u32(buf, offset, token);
+    }
+
+    Some(found.len())
+}
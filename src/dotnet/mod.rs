@@ -0,0 +1,286 @@
+//! User strings of .NET assemblies, which live in the `#US` metadata heap and are loaded by
+//! `ldstr` instructions.
+
+mod il;
+mod tables;
+
+use std::collections::{HashMap, HashSet};
+
+use goblin::pe::section_table::{IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ};
+
+use crate::error::{Error, Result};
+use crate::pe::{self, Image};
+use crate::resources::{Lookup, Reader};
+use crate::Translation;
+
+pub const IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR: usize = 14;
+
+/// Name of the section the metadata is moved to once its user strings grew
+pub const SECTION_NAME: &str = ".netmeta";
+
+const METADATA_SIGNATURE: u32 = 0x424a_5342;
+const COMIMAGE_FLAGS_STRONGNAMESIGNED: u32 = 0x8;
+const CLI_HEADER_SIZE: usize = 72;
+/// The table index of user string tokens, the low 24 bits are an offset in the heap
+const USER_STRING_TOKEN: u32 = 0x7000_0000;
+const MAX_HEAP_SIZE: usize = 0x100_0000;
+
+/// What translating the user strings did
+#[derive(Debug, Default)]
+pub struct UserStringCounts {
+    /// Strings whose translation was appended to the heap
+    pub replaced: usize,
+    /// `ldstr` instructions pointed to a translation
+    pub rewritten: usize,
+    /// Set once the metadata was moved to a new section
+    pub moved_to: Option<&'static str>,
+    /// The strong name signature is no longer valid
+    pub strong_name_signed: bool,
+    /// Strings replaced for each translation of the set, in the same order. Empty if nothing
+    /// was replaced.
+    pub translations: Vec<usize>,
+}
+
+struct Stream {
+    name: String,
+    data: Vec<u8>,
+}
+
+/// The metadata root and its streams
+struct Metadata {
+    /// Everything before the stream headers, kept as is
+    header: Vec<u8>,
+    streams: Vec<Stream>,
+}
+
+impl Metadata {
+    fn parse(bytes: &[u8]) -> Option<Metadata> {
+        let mut reader = Reader::new(bytes);
+
+        if pe::read_u32(reader.read_bytes(4)?, 0) != METADATA_SIGNATURE {
+            return None;
+        }
+        // Versions and reserved
+        reader.read_bytes(8)?;
+        let version_length = pe::read_u32(reader.read_bytes(4)?, 0) as usize;
+        reader.read_bytes(version_length)?;
+        // Flags
+        reader.read_bytes(2)?;
+        let count = reader.read_u16()?;
+        let header = bytes[.. reader.offset].to_vec();
+
+        let mut streams = Vec::with_capacity(count as usize);
+        for _ in 0 .. count {
+            let header = reader.read_bytes(8)?;
+            let offset = pe::read_u32(header, 0) as usize;
+            let size = pe::read_u32(header, 4) as usize;
+
+            let mut name = Vec::new();
+            loop {
+                match reader.read_bytes(1)?[0] {
+                    0 => break,
+                    byte => name.push(byte),
+                }
+            }
+            reader.align(4);
+
+            streams.push(Stream {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                data: bytes.get(offset .. offset.checked_add(size)?)?.to_vec(),
+            });
+        }
+
+        Some(Metadata {
+            header,
+            streams,
+        })
+    }
+
+    fn stream(&self, name: &str) -> Option<&Stream> {
+        self.streams.iter().find(|stream| stream.name == name)
+    }
+
+    fn build(&self) -> Vec<u8> {
+        let mut buf = self.header.clone();
+
+        let headers_size: usize = self.streams.iter()
+            .map(|stream| 8 + pe::align_up(stream.name.len() as u32 + 1, 4) as usize)
+            .sum();
+        let mut offset = buf.len() + headers_size;
+        for stream in self.streams.iter() {
+            let size = pe::align_up(stream.data.len() as u32, 4);
+            buf.extend_from_slice(&(offset as u32).to_le_bytes());
+            buf.extend_from_slice(&size.to_le_bytes());
+            buf.extend_from_slice(stream.name.as_bytes());
+            buf.push(0);
+            crate::resources::pad(&mut buf, 4);
+
+            offset += size as usize;
+        }
+
+        for stream in self.streams.iter() {
+            buf.extend_from_slice(&stream.data);
+            crate::resources::pad(&mut buf, 4);
+        }
+
+        buf
+    }
+}
+
+/// Reads the compressed unsigned integer at `offset`, returning it with its size.
+fn read_compressed(bytes: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let first = *bytes.get(offset)? as usize;
+
+    if first & 0x80 == 0 {
+        Some((first, 1))
+    } else if first & 0xc0 == 0x80 {
+        Some(((first & 0x3f) << 8 | *bytes.get(offset + 1)? as usize, 2))
+    } else if first & 0xe0 == 0xc0 {
+        let rest = bytes.get(offset + 1 .. offset + 4)?;
+        Some(((first & 0x1f) << 24 | (rest[0] as usize) << 16 | (rest[1] as usize) << 8 | rest[2] as usize, 4))
+    } else {
+        None
+    }
+}
+
+fn write_compressed(buf: &mut Vec<u8>, value: usize) {
+    if value < 0x80 {
+        buf.push(value as u8);
+    } else if value < 0x4000 {
+        buf.extend_from_slice(&(value as u16 | 0x8000).to_be_bytes());
+    } else {
+        buf.extend_from_slice(&(value as u32 | 0xc000_0000).to_be_bytes());
+    }
+}
+
+/// Appends a user string: its size, the UTF-16 text, and a final byte telling whether any
+/// character needs more than plain ASCII handling when comparing.
+fn write_user_string(buf: &mut Vec<u8>, text: &str) {
+    let units: Vec<u16> = text.encode_utf16().collect();
+    let special = units.iter().any(|&unit| matches!(unit, 0x01 ..= 0x08 | 0x0e ..= 0x1f | 0x27 | 0x2d | 0x7f ..= 0xffff));
+
+    write_compressed(buf, units.len() * 2 + 1);
+    for unit in units.iter() {
+        buf.extend_from_slice(&unit.to_le_bytes());
+    }
+    buf.push(special as u8);
+}
+
+/// Translates the user strings by appending their translations to the heap and pointing the
+/// `ldstr` instructions loading them there. The original strings are left in place, so that a
+/// method body that can't be decoded still works, untranslated.
+pub fn translate_user_strings(exe_buf: &mut Vec<u8>, translations: &[Translation], warnings: &mut Vec<String>) -> Result<UserStringCounts> {
+    let mut image = Image::parse(exe_buf)?;
+
+    let (cli_rva, _) = match image.data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_COM_DESCRIPTOR) {
+        Some(directory) => directory,
+        None => {
+            warnings.push("The executable isn't a .NET assembly".to_string());
+            return Ok(UserStringCounts::default());
+        },
+    };
+    let cli_header = image.rva_to_offset(cli_rva)
+        .ok_or_else(|| Error::malformed(format!("CLI header at RVA {:#x} isn't in any section", cli_rva)))?;
+    if cli_header + CLI_HEADER_SIZE > exe_buf.len() {
+        return Err(Error::out_of_bounds("CLI header", cli_header, CLI_HEADER_SIZE));
+    }
+    // Read before the buffer is rewritten
+    let strong_name_signed = pe::read_u32(exe_buf, cli_header + 16) & COMIMAGE_FLAGS_STRONGNAMESIGNED != 0;
+
+    let metadata_rva = pe::read_u32(exe_buf, cli_header + 8);
+    let metadata_size = pe::read_u32(exe_buf, cli_header + 12) as usize;
+    let metadata_offset = image.rva_to_offset(metadata_rva)
+        .filter(|&offset| offset + metadata_size <= exe_buf.len())
+        .ok_or_else(|| Error::malformed(format!("Metadata at RVA {:#x} isn't in any section", metadata_rva)))?;
+
+    let mut metadata = Metadata::parse(&exe_buf[metadata_offset .. metadata_offset + metadata_size])
+        .ok_or_else(|| Error::malformed("Truncated or invalid .NET metadata".to_string()))?;
+
+    let mut heap = match metadata.stream("#US") {
+        Some(stream) => stream.data.clone(),
+        None => {
+            warnings.push("The assembly has no user strings".to_string());
+            return Ok(UserStringCounts::default());
+        },
+    };
+
+    // Maps the tokens of the translated strings to the tokens of their translations
+    let section = image.section_name_at(metadata_rva).unwrap_or_default();
+    let lookup = Lookup::new(translations, &section);
+    let mut tokens = HashMap::new();
+    let original_size = heap.len();
+    let mut offset = 1;
+    while offset < original_size {
+        let (size, size_size) = match read_compressed(&heap, offset) {
+            Some(size) => size,
+            None => break,
+        };
+        let start = offset + size_size;
+        let end = start + size;
+        if end > original_size {
+            break;
+        }
+
+        // The final byte isn't part of the text
+        let units: Vec<u16> = heap[start .. end - (size % 2)].chunks_exact(2)
+            .map(|unit| pe::read_u16(unit, 0))
+            .collect();
+        let translated = String::from_utf16(&units).ok()
+            .filter(|_| size > 0)
+            .and_then(|original| lookup.get(&original));
+        if let Some(translated) = translated {
+            tokens.insert(USER_STRING_TOKEN | offset as u32, USER_STRING_TOKEN | heap.len() as u32);
+            write_user_string(&mut heap, translated);
+        }
+
+        offset = end;
+    }
+
+    if tokens.is_empty() {
+        return Ok(UserStringCounts::default());
+    }
+    if heap.len() > MAX_HEAP_SIZE {
+        return Err(Error::malformed(format!("The translated user strings take {:#x} bytes, more than the {:#x} a token can address", heap.len(), MAX_HEAP_SIZE)));
+    }
+
+    let tables = metadata.stream("#~").or_else(|| metadata.stream("#-"))
+        .ok_or_else(|| Error::malformed("The metadata has no tables stream".to_string()))?;
+    let method_rvas = tables::method_rvas(&tables.data)
+        .ok_or_else(|| Error::malformed("Truncated metadata tables".to_string()))?;
+
+    let mut rewritten = 0;
+    let mut seen = HashSet::new();
+    for rva in method_rvas {
+        // Abstract and runtime provided methods have no body, and bodies can be shared
+        if rva == 0 || !seen.insert(rva) {
+            continue;
+        }
+
+        let body = match image.rva_to_offset(rva) {
+            Some(body) => body,
+            None => continue,
+        };
+        match il::rewrite_ldstr(exe_buf, body, &tokens) {
+            Some(count) => rewritten += count,
+            None => warnings.push(format!("The method body at RVA {:#x} can't be decoded, its strings stay untranslated", rva)),
+        }
+    }
+
+    metadata.streams.iter_mut()
+        .find(|stream| stream.name == "#US")
+        .unwrap()
+        .data = heap;
+    let rebuilt = metadata.build();
+
+    let new_rva = image.append_section(exe_buf, SECTION_NAME, &rebuilt, IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ, warnings)?;
+    pe::write_u32(exe_buf, cli_header + 8, new_rva);
+    pe::write_u32(exe_buf, cli_header + 12, rebuilt.len() as u32);
+
+    Ok(UserStringCounts {
+        replaced: tokens.len(),
+        rewritten,
+        moved_to: Some(SECTION_NAME),
+        strong_name_signed,
+        translations: lookup.replaced(),
+    })
+}
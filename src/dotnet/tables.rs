@@ -0,0 +1,74 @@
+//! Just enough of the `#~` metadata tables stream to find the method bodies.
+
+use crate::pe;
+use crate::resources::Reader;
+
+const MODULE: usize = 0x00;
+const TYPE_REF: usize = 0x01;
+const TYPE_DEF: usize = 0x02;
+const FIELD_PTR: usize = 0x03;
+const FIELD: usize = 0x04;
+const METHOD_PTR: usize = 0x05;
+const METHOD_DEF: usize = 0x06;
+const PARAM: usize = 0x08;
+const MODULE_REF: usize = 0x1a;
+const TYPE_SPEC: usize = 0x1b;
+const ASSEMBLY_REF: usize = 0x23;
+
+const HEAP_STRINGS_WIDE: u8 = 0x01;
+const HEAP_GUID_WIDE: u8 = 0x02;
+const HEAP_BLOB_WIDE: u8 = 0x04;
+/// An undocumented extra 4 bytes follow the row counts
+const EXTRA_DATA: u8 = 0x40;
+
+/// Returns the RVA of every method body, 0 for methods without one. The MethodDef table
+/// follows the first six tables, whose row sizes depend on the size of the heaps and tables
+/// they index.
+pub fn method_rvas(stream: &[u8]) -> Option<Vec<u32>> {
+    let mut reader = Reader::new(stream);
+
+    // Reserved, major and minor version
+    reader.read_bytes(6)?;
+    let heap_sizes = reader.read_bytes(2)?[0];
+    let valid = pe::read_u64(reader.read_bytes(8)?, 0);
+    // Sorted tables
+    reader.read_bytes(8)?;
+
+    let mut rows = [0usize; 64];
+    for (table, count) in rows.iter_mut().enumerate() {
+        if valid & (1 << table) != 0 {
+            *count = pe::read_u32(reader.read_bytes(4)?, 0) as usize;
+        }
+    }
+    if heap_sizes & EXTRA_DATA != 0 {
+        reader.read_bytes(4)?;
+    }
+
+    let heap_index = |flag| if heap_sizes & flag != 0 { 4 } else { 2 };
+    let string = heap_index(HEAP_STRINGS_WIDE);
+    let guid = heap_index(HEAP_GUID_WIDE);
+    let blob = heap_index(HEAP_BLOB_WIDE);
+    let index = |table: usize| if rows[table] < 0x10000 { 2 } else { 4 };
+    // The low bits of a coded index tell which of the tables it points to
+    let coded_index = |tables: &[usize], tag_bits: u32| {
+        if tables.iter().all(|&table| rows[table] < 1 << (16 - tag_bits)) { 2 } else { 4 }
+    };
+
+    let row_sizes = [
+        (MODULE, 2 + string + 3 * guid),
+        (TYPE_REF, coded_index(&[MODULE, MODULE_REF, ASSEMBLY_REF, TYPE_REF], 2) + 2 * string),
+        (TYPE_DEF, 4 + 2 * string + coded_index(&[TYPE_DEF, TYPE_REF, TYPE_SPEC], 2) + index(FIELD) + index(METHOD_DEF)),
+        (FIELD_PTR, index(FIELD)),
+        (FIELD, 2 + string + blob),
+        (METHOD_PTR, index(METHOD_DEF)),
+    ];
+    for &(table, row_size) in row_sizes.iter() {
+        reader.read_bytes(rows[table].checked_mul(row_size)?)?;
+    }
+
+    // RVA, implementation flags, flags, name, signature and parameter list
+    let method_def_size = 4 + 2 + 2 + string + blob + index(PARAM);
+    (0 .. rows[METHOD_DEF])
+        .map(|_| reader.read_bytes(method_def_size).map(|row| pe::read_u32(row, 0)))
+        .collect()
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+
+/// The ways a string can be stored in an executable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Utf16Le,
+    Utf8,
+    ShiftJis,
+    Gbk,
+    Big5,
+    Windows1252,
+}
+
+#[derive(Debug)]
+pub struct EncodeError {
+    pub character: char,
+    pub encoding: Encoding,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' (U+{:04X}) can't be represented in {}", self.character, self.character as u32, self.encoding)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf8 => "utf-8",
+            Encoding::ShiftJis => "shift-jis",
+            Encoding::Gbk => "gbk",
+            Encoding::Big5 => "big5",
+            Encoding::Windows1252 => "cp1252",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl Encoding {
+    /// Names accepted on the command line and in the CSV encoding column.
+    pub const NAMES: &'static [&'static str] = &[
+        "utf-16le", "utf-8", "shift-jis", "cp932", "gbk", "cp936", "big5", "cp950", "cp1252",
+    ];
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-16le" | "utf-16" | "utf16le" | "utf16" => Some(Encoding::Utf16Le),
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            // encoding_rs' Shift_JIS is Microsoft's variant, so it covers CP932 as well
+            "shift-jis" | "shiftjis" | "sjis" | "cp932" | "windows-31j" => Some(Encoding::ShiftJis),
+            "gbk" | "cp936" => Some(Encoding::Gbk),
+            "big5" | "cp950" => Some(Encoding::Big5),
+            "cp1252" | "windows-1252" => Some(Encoding::Windows1252),
+            _ => None,
+        }
+    }
+
+    /// Size in bytes of a code unit, and therefore of the null terminator.
+    pub fn unit_size(&self) -> usize {
+        match self {
+            Encoding::Utf16Le => 2,
+            _ => 1,
+        }
+    }
+
+    fn codec(&self) -> &'static encoding_rs::Encoding {
+        match self {
+            Encoding::Utf16Le => encoding_rs::UTF_16LE,
+            Encoding::Utf8 => encoding_rs::UTF_8,
+            Encoding::ShiftJis => encoding_rs::SHIFT_JIS,
+            Encoding::Gbk => encoding_rs::GBK,
+            Encoding::Big5 => encoding_rs::BIG5,
+            Encoding::Windows1252 => encoding_rs::WINDOWS_1252,
+        }
+    }
+
+    /// Encodes `text` followed by a null terminator.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, EncodeError> {
+        let mut end = match self {
+            // encoding_rs never encodes to UTF-16, as per the WHATWG spec
+            Encoding::Utf16Le => text.encode_utf16()
+                .flat_map(|unit| unit.to_le_bytes().to_vec())
+                .collect(),
+            _ => {
+                let mut encoder = self.codec().new_encoder();
+                let capacity = encoder.max_buffer_length_from_utf8_without_replacement(text.len())
+                    .expect("string too long to be encoded");
+                let mut end = Vec::with_capacity(capacity);
+
+                let (result, _) = encoder.encode_from_utf8_to_vec_without_replacement(text, &mut end, true);
+                if let encoding_rs::EncoderResult::Unmappable(character) = result {
+                    return Err(EncodeError {
+                        character,
+                        encoding: *self,
+                    });
+                }
+
+                end
+            },
+        };
+
+        end.extend(std::iter::repeat_n(0, self.unit_size()));
+
+        Ok(end)
+    }
+
+    /// Decodes `bytes`, which must not include the null terminator. Returns `None` if `bytes`
+    /// isn't valid in this encoding.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        self.codec()
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .map(|text| text.into_owned())
+    }
+}
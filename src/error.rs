@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io;
+
+use crate::encoding::EncodeError;
+use crate::patch::PatchError;
+
+/// Everything that can stop a translation
+#[derive(Debug)]
+pub enum Error {
+    /// A file couldn't be read or written
+    Io { path: String, error: io::Error },
+    Csv { path: String, error: csv::Error },
+    /// The translation file can't be used, although it was read
+    Translations { path: String, message: String },
+    /// The executable is malformed
+    Parse(goblin::error::Error),
+    /// A structure of the executable goes past the end of the file
+    OutOfBounds { what: String, offset: usize, size: usize },
+    /// The file isn't a PE, ELF or Mach-O executable
+    UnsupportedFormat,
+    /// An option doesn't apply to this kind of executable
+    Unsupported(String),
+    Patch(PatchError),
+    /// A text has a character its encoding can't represent
+    Encode { text: String, error: EncodeError },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: &str, error: io::Error) -> Error {
+        Error::Io {
+            path: path.to_string(),
+            error,
+        }
+    }
+
+    pub fn csv(path: &str, error: csv::Error) -> Error {
+        Error::Csv {
+            path: path.to_string(),
+            error,
+        }
+    }
+
+    pub fn translations(path: &str, message: String) -> Error {
+        Error::Translations {
+            path: path.to_string(),
+            message,
+        }
+    }
+
+    pub fn malformed(message: String) -> Error {
+        Error::Parse(goblin::error::Error::Malformed(message))
+    }
+
+    pub fn out_of_bounds(what: &str, offset: usize, size: usize) -> Error {
+        Error::OutOfBounds {
+            what: what.to_string(),
+            offset,
+            size,
+        }
+    }
+
+    pub fn encode(text: &str, error: EncodeError) -> Error {
+        Error::Encode {
+            text: text.to_string(),
+            error,
+        }
+    }
+
+    /// The exit code of the command line tool, so that scripts can tell errors apart. 1 is
+    /// left to invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => 2,
+            Error::Csv { .. } | Error::Translations { .. } => 3,
+            Error::Parse(_) => 4,
+            Error::UnsupportedFormat => 5,
+            Error::Unsupported(_) => 6,
+            Error::Patch(_) => 7,
+            Error::Encode { .. } => 8,
+            Error::OutOfBounds { .. } => 9,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "{}: {}", path, error),
+            Error::Csv { path, error } => write!(f, "{}: {}", path, error),
+            Error::Translations { path, message } => write!(f, "{}: {}", path, message),
+            Error::Parse(error) => write!(f, "The executable is malformed: {}", error),
+            Error::OutOfBounds { what, offset, size } => write!(f, "The executable is malformed: {} at offset {:#x} ({:#x} bytes) goes past the end of the file", what, offset, size),
+            Error::UnsupportedFormat => write!(f, "The file isn't a PE, ELF or Mach-O executable"),
+            Error::Unsupported(message) => write!(f, "{}", message),
+            Error::Patch(error) => write!(f, "{}", error),
+            Error::Encode { text, error } => write!(f, "Can't encode {:?}: {}", text, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { error, .. } => Some(error),
+            Error::Csv { error, .. } => Some(error),
+            Error::Parse(error) => Some(error),
+            Error::Encode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<goblin::error::Error> for Error {
+    fn from(error: goblin::error::Error) -> Error {
+        Error::Parse(error)
+    }
+}
+
+impl From<PatchError> for Error {
+    fn from(error: PatchError) -> Error {
+        Error::Patch(error)
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::encoding::Encoding;
+use crate::Section;
+
+pub struct ExtractedString {
+    pub text: String,
+    pub section: String,
+    /// The architecture of the section, in fat Mach-O files
+    pub architecture: Option<String>,
+    pub offset: usize,
+    /// RVA in PE files, virtual address otherwise
+    pub address: u64,
+    pub encoding: Encoding,
+    /// Bytes taken by the text without its null terminator, which is as much as a translation
+    /// can take
+    pub size: usize,
+}
+
+fn is_printable(c: char) -> bool {
+    !c.is_control() || c == '\t' || c == '\n' || c == '\r'
+}
+
+/// Finds every null-terminated string of at least `min_length` code units in `section` that is
+/// valid in `encoding`. Only strings aligned on the code unit size are considered, which is how
+/// compilers lay out wide strings.
+pub fn find_strings(exe_buf: &[u8], section: &Section, encoding: Encoding, min_length: usize) -> Vec<ExtractedString> {
+    let unit_size = encoding.unit_size();
+    let mut strings = Vec::new();
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (i, unit) in exe_buf[section.range()].chunks_exact(unit_size).enumerate() {
+        if unit.iter().any(|&byte| byte != 0) {
+            bytes.extend_from_slice(unit);
+            continue;
+        }
+
+        if bytes.len() >= min_length.max(1) * unit_size {
+            if let Some(text) = encoding.decode(&bytes) {
+                if text.chars().all(is_printable) {
+                    let start = i * unit_size - bytes.len();
+                    strings.push(ExtractedString {
+                        text,
+                        section: section.name.clone(),
+                        architecture: section.architecture.clone(),
+                        offset: section.offset + start,
+                        address: section.address + start as u64,
+                        encoding,
+                        size: bytes.len(),
+                    });
+                }
+            }
+        }
+
+        bytes.clear();
+    }
+
+    strings
+}
+
+/// The distinct texts of `strings` in the order they first appear, with all their occurrences.
+/// Templates have one entry per text rather than per occurrence, since a translation applies to
+/// every occurrence of its original text.
+pub fn group_by_text(strings: &[ExtractedString]) -> Vec<(&str, Vec<&ExtractedString>)> {
+    let mut indexes: HashMap<&str, usize> = HashMap::new();
+    let mut groups: Vec<(&str, Vec<&ExtractedString>)> = Vec::new();
+
+    for string in strings.iter() {
+        let index = *indexes.entry(&string.text).or_insert_with(|| {
+            groups.push((&string.text, Vec::new()));
+            groups.len() - 1
+        });
+        groups[index].1.push(string);
+    }
+
+    groups
+}
+
+/// Writes the strings in the format `TranslationSet::from_csv` reads, leaving the translation column
+/// empty, with a row per text as grouped by `group_by_text`. Returns the number of rows written.
+pub fn write_template(csv_path: &str, strings: &[ExtractedString]) -> csv::Result<usize> {
+    let fd = File::create(csv_path)?;
+    let writer = BufWriter::new(fd);
+
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+
+    let texts = group_by_text(strings);
+    for (text, _) in texts.iter() {
+        csv_writer.write_record([text, ""])?;
+    }
+
+    csv_writer.flush()?;
+
+    Ok(texts.len())
+}
@@ -0,0 +1,491 @@
+//! Finds strings in PE, ELF or Mach-O executables and replaces them with a translation.
+//!
+//! Load a [`TranslationSet`], then give the executable to a [`Patcher`]:
+//!
+//! ```no_run
+//! use translator::{CsvFormat, Options, Patcher, TranslationSet};
+//!
+//! let translations = TranslationSet::from_csv("strings.csv", &CsvFormat::default())?;
+//! let mut patcher = Patcher::new(translator::read_file("game.exe")?);
+//! let result = patcher.translate(&translations, &Options::default())?;
+//! println!("{} warnings", result.warnings.len());
+//! translator::write_file("game.translated.exe", patcher.patched())?;
+//! # Ok::<(), translator::Error>(())
+//! ```
+
+mod dotnet;
+mod error;
+mod mach;
+mod patcher;
+mod pe;
+mod relocate;
+mod resources;
+
+pub mod encoding;
+pub mod extract;
+pub mod matcher;
+pub mod patch;
+pub mod po;
+pub mod report;
+pub mod xliff;
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::fs::File;
+use std::ops::Range;
+
+use goblin::Object;
+use goblin::pe::section_table::IMAGE_SCN_CNT_INITIALIZED_DATA;
+use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHT_NOBITS, SHT_PROGBITS};
+
+pub use encoding::Encoding;
+pub use error::{Error, Result};
+pub use extract::ExtractedString;
+pub use matcher::{Action, OverflowPolicy};
+pub use relocate::SECTION_NAME as RELOCATION_SECTION_NAME;
+pub use patcher::{ChecksumPolicy, ConflictResult, EntryResult, OccurrenceResult, Options, PatchResult, Patcher, ResourceCounts, SectionSummary, UserStringCounts};
+
+pub fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    File::open(path)
+        .and_then(|mut fd| fd.read_to_end(&mut buffer))
+        .map_err(|error| Error::io(path, error))?;
+
+    Ok(buffer)
+}
+
+pub fn write_file(path: &str, bytes: &[u8]) -> Result<()> {
+    File::create(path)
+        .and_then(|fd| {
+            let mut writer = BufWriter::new(fd);
+            writer.write_all(bytes)?;
+            writer.flush()
+        })
+        .map_err(|error| Error::io(path, error))
+}
+
+pub struct Translation {
+    pub original: String,
+    pub translated: String,
+    /// Overrides the encoding of the `Options` for this translation only
+    pub encoding: Option<Encoding>,
+    /// Only translates the occurrences in the section of this name, which PO files give as the
+    /// context of an entry
+    pub section: Option<String>,
+}
+
+/// The translations of a CSV, PO or XLIFF file, in the order of its rows
+pub struct TranslationSet {
+    pub translations: Vec<Translation>,
+    /// A message for each row or entry that couldn't be read
+    pub rejected: Vec<String>,
+}
+
+impl TranslationSet {
+    pub fn new(translations: Vec<Translation>) -> TranslationSet {
+        TranslationSet {
+            translations,
+            rejected: Vec::new(),
+        }
+    }
+
+    /// Reads a PO file if `path` ends with `.po`, an XLIFF file if it ends with `.xlf` or
+    /// `.xliff`, a CSV file otherwise
+    pub fn from_file(path: &str, csv_format: &CsvFormat) -> Result<TranslationSet> {
+        let lowercase_path = path.to_lowercase();
+        if lowercase_path.ends_with(".po") {
+            TranslationSet::from_po(path)
+        } else if lowercase_path.ends_with(".xlf") || lowercase_path.ends_with(".xliff") {
+            TranslationSet::from_xliff(path)
+        } else {
+            TranslationSet::from_csv(path, csv_format)
+        }
+    }
+
+    pub fn from_csv(csv_path: &str, format: &CsvFormat) -> Result<TranslationSet> {
+        let fd = File::open(csv_path).map_err(|error| Error::io(csv_path, error))?;
+
+        TranslationSet::from_csv_reader(BufReader::new(fd), format)
+            .map_err(|message| Error::translations(csv_path, message))?
+            .usable(csv_path)
+    }
+
+    pub fn from_po(po_path: &str) -> Result<TranslationSet> {
+        let contents = std::fs::read_to_string(po_path).map_err(|error| Error::io(po_path, error))?;
+
+        po::read(&contents).usable(po_path)
+    }
+
+    pub fn from_xliff(xliff_path: &str) -> Result<TranslationSet> {
+        let contents = std::fs::read_to_string(xliff_path).map_err(|error| Error::io(xliff_path, error))?;
+
+        xliff::read(&contents)
+            .map_err(|message| Error::translations(xliff_path, message))?
+            .usable(xliff_path)
+    }
+
+    /// Fails if not a single translation was read from `path`, since nothing would be
+    /// translated
+    fn usable(self, path: &str) -> Result<TranslationSet> {
+        if !self.translations.is_empty() {
+            return Ok(self);
+        }
+
+        let message = match self.rejected.first() {
+            Some(first) => format!("None of the translations can be read, {} rejected. The first: {}", self.rejected.len(), first),
+            None => "The file has no translations".to_string(),
+        };
+        Err(Error::translations(path, message))
+    }
+
+    /// Reads rows of original text, translated text and an optional encoding, in the columns
+    /// given by `format`. Other columns are ignored. Rows without a translation are left out
+    /// silently, rows that can't be read are left out and recorded in `rejected`, with their line
+    /// in the file. Fails if the header doesn't have the columns of `format`.
+    pub fn from_csv_reader<R: Read>(reader: R, format: &CsvFormat) -> std::result::Result<TranslationSet, String> {
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .has_headers(format.header)
+            .flexible(true)
+            .from_reader(reader);
+
+        let columns = if format.header {
+            csv_reader.headers()
+                .map_err(|error| format!("Error reading the header: {}", error))
+                .and_then(|header| format.columns(header))
+        } else {
+            Ok((0, 1, Some(2)))
+        };
+        let (original_column, translated_column, encoding_column) = columns?;
+
+        let mut rejected = Vec::new();
+        let translations = csv_reader.records()
+            .filter_map(|result| {
+                let record = match result {
+                    Ok(record) => record,
+                    Err(error) => {
+                        rejected.push(format!("An error occurred: {}", error));
+                        return None;
+                    },
+                };
+                let line = record.position().map_or(0, |position| position.line());
+
+                let original = if let Some(string) = record.get(original_column) {
+                    string.to_string()
+                } else {
+                    rejected.push(format!("Line {} doesn't have an original text column: {:?}", line, record));
+                    return None;
+                };
+
+                let translated = if let Some(string) = record.get(translated_column) {
+                    string.to_string()
+                } else {
+                    rejected.push(format!("Line {} doesn't have a translated text column: {:?}", line, record));
+                    return None;
+                };
+
+                // Rows of a template that aren't translated yet
+                if translated.is_empty() {
+                    return None;
+                }
+
+                let encoding = match encoding_column.and_then(|column| record.get(column)) {
+                    Some(name) if !name.trim().is_empty() => if let Some(encoding) = Encoding::from_name(name) {
+                        Some(encoding)
+                    } else {
+                        rejected.push(format!("Unknown encoding {} line {}", name, line));
+                        return None;
+                    },
+                    _ => None,
+                };
+
+                let translation = Translation {
+                    original,
+                    translated,
+                    encoding,
+                    section: None,
+                };
+
+                Some(translation)
+            })
+            .collect();
+
+        Ok(TranslationSet {
+            translations,
+            rejected,
+        })
+    }
+}
+
+/// Where the texts are in the rows of a CSV file
+pub struct CsvFormat {
+    /// The first row names the columns. Otherwise, the original text, translated text and
+    /// encoding are the first three columns.
+    pub header: bool,
+    pub original_column: String,
+    pub translated_column: String,
+    /// This column is optional
+    pub encoding_column: String,
+}
+
+impl CsvFormat {
+    /// The indexes of the original text, translated text and encoding columns in `header`
+    fn columns(&self, header: &csv::StringRecord) -> std::result::Result<(usize, usize, Option<usize>), String> {
+        let find = |name: &str| header.iter().position(|column| column.trim().eq_ignore_ascii_case(name));
+
+        let original = find(&self.original_column)
+            .ok_or_else(|| format!("The header doesn't have a column named {}: {:?}", self.original_column, header))?;
+        let translated = find(&self.translated_column)
+            .ok_or_else(|| format!("The header doesn't have a column named {}: {:?}", self.translated_column, header))?;
+
+        Ok((original, translated, find(&self.encoding_column)))
+    }
+}
+
+impl Default for CsvFormat {
+    fn default() -> CsvFormat {
+        CsvFormat {
+            header: false,
+            original_column: "original".to_string(),
+            translated_column: "translated".to_string(),
+            encoding_column: "encoding".to_string(),
+        }
+    }
+}
+
+/// The executable formats strings can be translated in
+enum Binary<'a> {
+    Pe(goblin::pe::PE<'a>),
+    Elf(goblin::elf::Elf<'a>),
+    /// Every architecture of a Mach-O file, or the only one if it isn't fat
+    Mach(Vec<mach::Slice<'a>>),
+}
+
+impl<'a> Binary<'a> {
+    /// Windows stores most strings as UTF-16, and Linux as UTF-8
+    fn default_encoding(&self) -> Encoding {
+        match self {
+            Binary::Pe(_) => Encoding::Utf16Le,
+            Binary::Elf(_) | Binary::Mach(_) => Encoding::Utf8,
+        }
+    }
+}
+
+/// Reads an unsigned field of `size` bytes, if it is in the buffer
+fn read_uint(buf: &[u8], offset: usize, size: usize, little_endian: bool) -> Option<u64> {
+    let bytes = buf.get(offset .. offset.checked_add(size)?)?;
+    let fold = |value: u64, &byte: &u8| value << 8 | byte as u64;
+
+    if little_endian {
+        Some(bytes.iter().rev().fold(0, fold))
+    } else {
+        Some(bytes.iter().fold(0, fold))
+    }
+}
+
+/// goblin reserves room for every program and section header the ELF header announces before
+/// reading them, so tables that can't be in the file are rejected first
+fn check_elf_tables(exe_buf: &[u8]) -> Result<()> {
+    use goblin::elf::header::{self, header32, header64, Header};
+
+    let is_64 = exe_buf.get(header::EI_CLASS) == Some(&header::ELFCLASS64);
+    let header: Header = if is_64 {
+        header64::Header::parse(exe_buf)?.into()
+    } else {
+        header32::Header::parse(exe_buf)?.into()
+    };
+    let little_endian = header.e_ident[header::EI_DATA] == header::ELFDATA2LSB;
+
+    // Past 0xff00 sections, the count is in the size of the first section header
+    let section_count = if header.e_shnum == 0 && header.e_shoff != 0 {
+        let (size_offset, size) = if is_64 { (32, 8) } else { (20, 4) };
+        (header.e_shoff as usize).checked_add(size_offset)
+            .and_then(|offset| read_uint(exe_buf, offset, size, little_endian))
+            .unwrap_or(0)
+    } else {
+        header.e_shnum as u64
+    };
+
+    // goblin reads entries of the standard size, whatever e_phentsize and e_shentsize say
+    let (program_header_size, section_header_size) = if is_64 { (56, 64) } else { (32, 40) };
+    let tables = [
+        ("Program headers", header.e_phoff, header.e_phnum as u64 * program_header_size),
+        ("Section headers", header.e_shoff, section_count.saturating_mul(section_header_size)),
+    ];
+    for &(what, offset, size) in tables.iter() {
+        if size != 0 && offset.saturating_add(size) > exe_buf.len() as u64 {
+            return Err(Error::out_of_bounds(what, offset as usize, size as usize));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_binary(exe_buf: &[u8]) -> Result<Binary<'_>> {
+    if exe_buf.starts_with(goblin::elf::header::ELFMAG) {
+        check_elf_tables(exe_buf)?;
+    }
+
+    match Object::parse(exe_buf)? {
+        Object::PE(pe) => {
+            Ok(Binary::Pe(pe))
+        },
+        Object::Elf(elf) => {
+            Ok(Binary::Elf(elf))
+        },
+        Object::Mach(mach) => {
+            Ok(Binary::Mach(mach::slices(mach, exe_buf)?))
+        },
+        _ => Err(Error::UnsupportedFormat),
+    }
+}
+
+/// Which sections of the executable are searched for strings
+pub enum SectionFilter {
+    /// `.rdata` in PE files, `.rodata` in ELF files, `__TEXT,__cstring` in Mach-O files
+    ReadOnlyData,
+    Named(Vec<String>),
+    InitializedData,
+}
+
+impl SectionFilter {
+    fn matches_name(&self, name: &str, read_only_data: &str) -> bool {
+        match self {
+            SectionFilter::ReadOnlyData => name == read_only_data,
+            SectionFilter::Named(names) => names.iter().any(|selected| selected == name),
+            SectionFilter::InitializedData => false,
+        }
+    }
+}
+
+pub struct Section {
+    /// The same in every architecture of a fat Mach-O file, which translations are scoped by
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+    /// Address of the section once loaded: an RVA in PE files, a virtual address otherwise
+    pub address: u64,
+    /// The architecture the section belongs to, in fat Mach-O files
+    pub architecture: Option<String>,
+}
+
+impl Section {
+    /// The name followed by the architecture, if any, for messages
+    pub fn label(&self) -> String {
+        match &self.architecture {
+            Some(architecture) => format!("{} ({})", self.name, architecture),
+            None => self.name.clone(),
+        }
+    }
+
+    fn range(&self) -> Range<usize> {
+        self.offset .. self.offset + self.size
+    }
+
+    fn overlaps(&self, other: &Section) -> bool {
+        self.offset < other.offset + other.size && other.offset < self.offset + self.size
+    }
+}
+
+fn select_sections(binary: &Binary, filter: &SectionFilter) -> Vec<Section> {
+    match binary {
+        Binary::Pe(pe_object) => pe_object.sections.iter()
+            .map(|section| (pe::section_name(section), section))
+            .filter(|(name, section)| match filter {
+                SectionFilter::InitializedData => section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0,
+                _ => filter.matches_name(name, ".rdata"),
+            })
+            .map(|(name, section)| Section {
+                name,
+                offset: section.pointer_to_raw_data as usize,
+                size: section.size_of_raw_data as usize,
+                address: section.virtual_address as u64,
+                architecture: None,
+            })
+            .collect(),
+        Binary::Elf(elf_object) => elf_object.section_headers.iter()
+            // NOBITS sections, like .bss, take no room in the file
+            .filter(|section| section.sh_type != SHT_NOBITS)
+            .filter_map(|section| {
+                let name = elf_object.shdr_strtab.get(section.sh_name)?.ok()?;
+
+                let selected = match filter {
+                    SectionFilter::InitializedData => section.sh_type == SHT_PROGBITS
+                        && section.sh_flags & SHF_ALLOC as u64 != 0
+                        && section.sh_flags & SHF_EXECINSTR as u64 == 0,
+                    _ => filter.matches_name(name, ".rodata"),
+                };
+
+                if selected {
+                    Some(Section {
+                        name: name.to_string(),
+                        offset: section.sh_offset as usize,
+                        size: section.sh_size as usize,
+                        address: section.sh_addr,
+                        architecture: None,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect(),
+        Binary::Mach(slices) => mach::select_sections(slices, filter),
+    }
+}
+
+/// Only keeps the sections that can be searched safely. The headers of truncated or hostile
+/// executables may put sections past the end of the file, or make them overlap, which would
+/// translate the same bytes twice.
+fn check_sections(sections: Vec<Section>, file_size: usize, warnings: &mut Vec<String>) -> Vec<Section> {
+    let mut checked: Vec<Section> = Vec::with_capacity(sections.len());
+
+    for mut section in sections {
+        // Sections of uninitialized data have nothing in the file
+        if section.size == 0 {
+            continue;
+        }
+        if section.offset >= file_size {
+            warnings.push(format!("Section {} starts at offset {:#x}, past the end of the file. Skipping it.", section.label(), section.offset));
+            continue;
+        }
+        if section.size > file_size - section.offset {
+            warnings.push(format!("Section {} goes past the end of the file, only its first {:#x} bytes are searched", section.label(), file_size - section.offset));
+            section.size = file_size - section.offset;
+        }
+        if let Some(other) = checked.iter().find(|other| other.overlaps(&section)) {
+            warnings.push(format!("Section {} overlaps section {}. Skipping it.", section.label(), other.label()));
+            continue;
+        }
+
+        checked.push(section);
+    }
+
+    checked
+}
+
+/// Finds the strings of the selected sections, in `encoding` or the usual one for the
+/// executable format.
+pub fn extract_strings(exe_buf: &[u8], encoding: Option<Encoding>, filter: &SectionFilter, min_length: usize, warnings: &mut Vec<String>) -> Result<Vec<ExtractedString>> {
+    let binary = parse_binary(exe_buf)?;
+    let encoding = encoding.unwrap_or_else(|| binary.default_encoding());
+
+    let mut strings = Vec::new();
+    for section in check_sections(select_sections(&binary, filter), exe_buf.len(), warnings) {
+        strings.extend(extract::find_strings(exe_buf, &section, encoding, min_length));
+    }
+
+    Ok(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_rows_without_translation() {
+        let csv = "Open,Ouvrir\nQuit,\nHello world,\"\"\n";
+        let set = TranslationSet::from_csv_reader(csv.as_bytes(), &CsvFormat::default()).unwrap();
+
+        let originals: Vec<&str> = set.translations.iter().map(|translation| translation.original.as_str()).collect();
+        assert_eq!(originals, vec!["Open"]);
+        assert!(set.rejected.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! Mach-O executables. Fat binaries hold one Mach-O per architecture, which all have to be
+//! translated the same way, and CFString constants store the length of the text they point to.
+
+use std::collections::{HashMap, HashSet};
+
+use goblin::mach::{Mach, MachO};
+use goblin::mach::constants::cputype::get_arch_name_from_types;
+
+use crate::error::{Error, Result};
+use crate::matcher::{Action, Entry, SectionResult};
+use crate::pe;
+use crate::{Section, SectionFilter};
+
+const SECTION_TYPE: u32 = 0xff;
+const S_ZEROFILL: u32 = 0x1;
+const S_GB_ZEROFILL: u32 = 0xc;
+const S_THREAD_LOCAL_ZEROFILL: u32 = 0x12;
+const S_ATTR_PURE_INSTRUCTIONS: u32 = 0x8000_0000;
+const S_ATTR_SOME_INSTRUCTIONS: u32 = 0x400;
+
+/// The Mach-O of one architecture
+pub struct Slice<'a> {
+    /// Only set in fat binaries
+    pub architecture: Option<String>,
+    /// Where the Mach-O starts in the file
+    pub offset: usize,
+    pub macho: MachO<'a>,
+}
+
+/// A CFString constant of a `__cfstring` section, which is made of four pointer sized fields:
+/// the class, flags, a pointer to the text, and its length in code units.
+pub struct CfString {
+    length_offset: usize,
+    pointer_size: usize,
+    text_offset: usize,
+}
+
+/// The Mach-O of each architecture. goblin panics on fat headers pointing past the end of the
+/// file, so they are checked here first.
+pub fn slices<'a>(mach: Mach<'a>, buf: &[u8]) -> Result<Vec<Slice<'a>>> {
+    match mach {
+        Mach::Binary(macho) => Ok(vec![Slice {
+            architecture: None,
+            offset: 0,
+            macho,
+        }]),
+        Mach::Fat(multi_arch) => {
+            let mut slices = Vec::new();
+
+            for (i, arch) in multi_arch.iter_arches().enumerate() {
+                let arch = arch?;
+                if arch.offset as u64 + arch.size as u64 > buf.len() as u64 {
+                    return Err(Error::out_of_bounds("Fat architecture", arch.offset as usize, arch.size as usize));
+                }
+                let architecture = get_arch_name_from_types(arch.cputype, arch.cpusubtype)
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("cputype {:#x}", arch.cputype));
+
+                slices.push(Slice {
+                    architecture: Some(architecture),
+                    offset: arch.offset as usize,
+                    macho: multi_arch.get(i)?,
+                });
+            }
+
+            Ok(slices)
+        },
+    }
+}
+
+/// Sections that hold neither code nor zeroes
+fn is_data(section: &goblin::mach::segment::Section) -> bool {
+    let section_type = section.flags & SECTION_TYPE;
+
+    section.offset != 0
+        && section.flags & (S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS) == 0
+        && section_type != S_ZEROFILL
+        && section_type != S_GB_ZEROFILL
+        && section_type != S_THREAD_LOCAL_ZEROFILL
+}
+
+/// The sections of every architecture, named `segment,section` like Apple's tools do.
+fn sections<'a>(slice: &'a Slice) -> impl Iterator<Item = (String, goblin::mach::segment::Section)> + 'a {
+    slice.macho.segments.iter()
+        .flat_map(|segment| segment.sections().unwrap_or_default())
+        .filter_map(|(section, _)| {
+            let name = format!("{},{}", section.segname().ok()?, section.name().ok()?);
+            Some((name, section))
+        })
+}
+
+pub fn select_sections(slices: &[Slice], filter: &SectionFilter) -> Vec<Section> {
+    let mut selected = Vec::new();
+
+    for slice in slices.iter() {
+        for (name, section) in sections(slice) {
+            let is_selected = match filter {
+                SectionFilter::InitializedData => is_data(&section),
+                _ => filter.matches_name(&name, "__TEXT,__cstring"),
+            };
+            if !is_selected {
+                continue;
+            }
+
+            selected.push(Section {
+                name,
+                offset: slice.offset + section.offset as usize,
+                size: section.size as usize,
+                address: section.addr,
+                architecture: slice.architecture.clone(),
+            });
+        }
+    }
+
+    selected
+}
+
+/// Finds the CFString constants of every architecture whose text is in the file. Only
+/// little-endian architectures with plain pointers are supported, a warning is added for the
+/// others since the length of their CFStrings can't be updated.
+pub fn cf_strings(buf: &[u8], slices: &[Slice], warnings: &mut Vec<String>) -> Vec<CfString> {
+    let mut cf_strings = Vec::new();
+
+    for slice in slices.iter() {
+        let has_cf_strings = sections(slice).any(|(name, section)| name.ends_with(",__cfstring") && section.size > 0);
+        let unreadable = || match &slice.architecture {
+            Some(architecture) => format!("The CFStrings of {} can't be read, their length isn't updated when their text is translated", architecture),
+            None => "The CFStrings can't be read, their length isn't updated when their text is translated".to_string(),
+        };
+
+        if !slice.macho.little_endian {
+            if has_cf_strings {
+                warnings.push(unreadable());
+            }
+            continue;
+        }
+        let found = cf_strings.len();
+
+        let pointer_size = if slice.macho.is_64 { 8 } else { 4 };
+        let read_pointer = |offset| if pointer_size == 8 {
+            pe::read_u64(buf, offset)
+        } else {
+            pe::read_u32(buf, offset) as u64
+        };
+
+        let all_sections: Vec<_> = sections(slice).map(|(_, section)| section).collect();
+        let address_to_offset = |address: u64| all_sections.iter()
+            .find(|section| section.offset != 0 && address >= section.addr && address - section.addr < section.size)
+            .map(|section| slice.offset + section.offset as usize + (address - section.addr) as usize);
+
+        for (name, section) in sections(slice) {
+            if !name.ends_with(",__cfstring") {
+                continue;
+            }
+
+            let start = slice.offset + section.offset as usize;
+            let end = std::cmp::min(start + section.size as usize, buf.len());
+            let mut offset = start;
+            while offset + 4 * pointer_size <= end {
+                if let Some(text_offset) = address_to_offset(read_pointer(offset + 2 * pointer_size)) {
+                    cf_strings.push(CfString {
+                        length_offset: offset + 3 * pointer_size,
+                        pointer_size,
+                        text_offset,
+                    });
+                }
+
+                offset += 4 * pointer_size;
+            }
+        }
+
+        // Chained fixups and other encoded pointers don't point anywhere in the file
+        if has_cf_strings && cf_strings.len() == found {
+            warnings.push(unreadable());
+        }
+    }
+
+    cf_strings
+}
+
+/// File offsets of the texts CFStrings point to
+pub fn references(cf_strings: &[CfString]) -> Vec<usize> {
+    cf_strings.iter().map(|cf_string| cf_string.text_offset).collect()
+}
+
+/// Writes the new length of the CFStrings whose text was replaced. `replaced` holds the file
+/// offset of every replaced text. Returns the number of CFStrings updated.
+pub fn update_cf_strings(buf: &mut [u8], cf_strings: &[CfString], replaced: &[(usize, &Entry)]) -> usize {
+    let replaced: HashMap<usize, &Entry> = replaced.iter().cloned().collect();
+    let mut updated = 0;
+
+    for cf_string in cf_strings.iter() {
+        let entry = match replaced.get(&cf_string.text_offset) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        // The terminator isn't counted
+        let length = (entry.translated.len() / entry.unit_size - 1) as u64;
+        if cf_string.pointer_size == 8 {
+            pe::write_u64(buf, cf_string.length_offset, length);
+        } else {
+            pe::write_u32(buf, cf_string.length_offset, length as u32);
+        }
+        updated += 1;
+    }
+
+    updated
+}
+
+/// Leaves out the occurrences of C strings used by a CFString whose translation isn't ASCII,
+/// since CFStrings read them as ASCII. `results` are those of `sections`.
+pub fn skip_non_ascii(cf_strings: &[CfString], sections: &[Section], results: &mut [SectionResult], entries: &[Entry]) {
+    let texts: HashSet<usize> = cf_strings.iter().map(|cf_string| cf_string.text_offset).collect();
+
+    for (section, result) in sections.iter().zip(results.iter_mut()) {
+        for occurrence in result.occurrences.iter_mut() {
+            let entry = &entries[occurrence.entry];
+            let replaced = occurrence.action == Action::Replaced || occurrence.action == Action::Overwritten;
+
+            if replaced && entry.unit_size == 1 && !entry.translation.translated.is_ascii() && texts.contains(&(section.offset + occurrence.offset)) {
+                occurrence.action = Action::NonAsciiCfString;
+            }
+        }
+    }
+}
+
+/// In fat binaries, leaves out the translations that aren't replaced the same number of times
+/// in every architecture, so that they all behave the same.
+pub fn skip_inconsistent(sections: &[Section], results: &mut [SectionResult], entries: usize) {
+    let mut replaced: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (section, result) in sections.iter().zip(results.iter()) {
+        let architecture = match &section.architecture {
+            Some(architecture) => architecture,
+            None => return,
+        };
+
+        let counts = replaced.entry(architecture).or_insert_with(|| vec![0; entries]);
+        for occurrence in result.occurrences.iter() {
+            if occurrence.action == Action::Replaced || occurrence.action == Action::Overwritten {
+                counts[occurrence.entry] += 1;
+            }
+        }
+    }
+
+    let inconsistent: Vec<bool> = (0 .. entries)
+        .map(|entry| {
+            let mut counts = replaced.values().map(|counts| counts[entry]);
+            let first = counts.next();
+            counts.any(|count| Some(count) != first)
+        })
+        .collect();
+
+    for result in results.iter_mut() {
+        for occurrence in result.occurrences.iter_mut() {
+            if inconsistent[occurrence.entry] && (occurrence.action == Action::Replaced || occurrence.action == Action::Overwritten) {
+                occurrence.action = Action::Inconsistent;
+            }
+        }
+    }
+}
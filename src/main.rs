@@ -1,196 +1,481 @@
-use goblin::Object;
-use csv;
-use clap::{App, Arg};
-use std::io::{Read, BufReader, Write, BufWriter};
-use std::fs::File;
-
-struct Translation {
-    original: String,
-    translated: String,
-}
+use std::env;
+use std::ffi::OsString;
 
-fn load_exe(exe_path: &str) -> std::io::Result<Vec<u8>> {
-    let mut fd = File::open(exe_path)?;
-    let mut buffer = Vec::new();
-    fd.read_to_end(&mut buffer)?;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-    Ok(buffer)
-}
+use translator::{patch, read_file, write_file, Action, ChecksumPolicy, CsvFormat, Encoding, Error, Options, OverflowPolicy, Patcher, Result, SectionFilter, TranslationSet};
+use translator::{extract, po, xliff};
+use translator::report::Report;
 
-fn load_translations(csv_path: &str) -> std::io::Result<Vec<Translation>> {
-    let fd = File::open(csv_path)?;
-    let reader = BufReader::new(fd);
-
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .from_reader(reader);
-
-    let translations = csv_reader.records()
-        .enumerate()
-        .filter_map(|(i, result)| {
-            let record = if result.is_err() {
-                println!("An error occurred: {}", result.err().unwrap());
-                return None;
-            } else {
-                result.unwrap()
-            };
-
-            if record.len() != 2 {
-                println!("Line {} doesn't have 2 columns: {:?}", i, record);
-                return None;
-            }
-            
-            let original = if let Some(string) = record.get(0) {
-                string.to_string()
-            } else {
-                println!("Error getting column 0 line {}", i);
-                return None;
-            };
+/// The encoding given on the command line, if any
+fn encoding(matches: &ArgMatches) -> Option<Encoding> {
+    matches.value_of("encoding").map(|name| Encoding::from_name(name).unwrap())
+}
 
-            let translated = if let Some(string) = record.get(1) {
-                string.to_string()
-            } else {
-                println!("Error getting column 1 line {}", i);
-                return None;
-            };
+/// Prints the bytes from `start` to `end` with a line of context on each side, 16 bytes per
+/// line, in hexadecimal and as ASCII.
+fn hexdump(buf: &[u8], start: usize, end: usize) {
+    let first_line = (start / 16).saturating_sub(1) * 16;
+    let last_line = std::cmp::min(end.next_multiple_of(16) + 16, buf.len());
 
-            let translation = Translation {
-                original,
-                translated,
-            };
+    for line_start in (first_line .. last_line).step_by(16) {
+        let line = &buf[line_start .. std::cmp::min(line_start + 16, last_line)];
 
-            Some(translation)
-        })
-        .collect();
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = line.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
 
-    Ok(translations)
+        println!("  {:08x}  {:<47}  |{}|", line_start, hex.join(" "), ascii);
+    }
 }
 
-fn parse_pe_obj(exe_buf: &Vec<u8>) -> goblin::error::Result<goblin::pe::PE> {
-    match Object::parse(exe_buf)? {
-        Object::PE(pe) => {
-            Ok(pe)
+fn translate_command(matches: &ArgMatches) -> Result<()> {
+    let exe_path = matches.value_of("EXE_FILE").unwrap();
+    let translation_path = matches.value_of("TRANSLATION_FILE").unwrap();
+    let write_patch = matches.value_of("output format") == Some("bps");
+    let default_out_path = if write_patch {
+        format!("{}.bps", exe_path)
+    } else {
+        format!("{}.translated", exe_path)
+    };
+    let out_path = matches.value_of("OUT_FILE").unwrap_or(&default_out_path);
+
+    let options = Options {
+        encoding: encoding(matches),
+        sections: section_filter(matches),
+        overflow_policy: if matches.is_present("relocate") {
+            OverflowPolicy::Relocate
+        } else if matches.is_present("potentially harmful") {
+            OverflowPolicy::Overwrite
+        } else {
+            OverflowPolicy::Skip
         },
-        _ => {
-            use std::io::ErrorKind;
-            println!("Wrong exe file type");
-            Err(std::io::Error::new(ErrorKind::InvalidData, "Wrong exe file type")).map_err(goblin::error::Error::IO)
+        allow_substrings: matches.is_present("allow substrings"),
+        slack_alignment: matches.value_of("slack alignment").unwrap().parse::<usize>().unwrap(),
+        resources: matches.is_present("resources"),
+        dotnet: matches.is_present("dotnet"),
+        strip_signature: matches.is_present("strip signature"),
+        checksum: match matches.value_of("checksum").unwrap() {
+            "always" => ChecksumPolicy::Always,
+            "never" => ChecksumPolicy::Never,
+            _ => ChecksumPolicy::IfPresent,
+        },
+    };
+
+    let csv_format = CsvFormat {
+        header: matches.is_present("header"),
+        original_column: matches.value_of("original column").unwrap().to_string(),
+        translated_column: matches.value_of("translated column").unwrap().to_string(),
+        encoding_column: matches.value_of("encoding column").unwrap().to_string(),
+    };
+    let translations = TranslationSet::from_file(translation_path, &csv_format)?;
+    translations.rejected.iter().for_each(|message| println!("{}", message));
+
+    let mut patcher = Patcher::new(read_file(exe_path)?);
+    let result = patcher.translate(&translations, &options)?;
+
+    for (translation, entry) in translations.translations.iter().zip(result.entries.iter()) {
+        if let Some(reason) = &entry.skipped {
+            println!("WARNING: Skipping the translation of {:?}: {}", translation.original, reason);
         }
     }
-}
 
-fn string_to_utf16_vec(original: &str) -> Vec<u8> {
-    let mut utf16le: Vec<u16> = original.encode_utf16().collect();
-    utf16le.push(0);
+    for conflict in result.conflicts.iter() {
+        let original = &translations.translations[conflict.translation].original;
+        let kept_original = &translations.translations[conflict.kept_translation].original;
+        println!("WARNING: {} at {:#x} overlaps {} at {:#x} in {}. Only the latter is replaced.", original, conflict.offset, kept_original, conflict.kept_offset, conflict.section);
+    }
 
-    let mut end = Vec::new();
-    utf16le.into_iter()
-        .for_each(|v| {
-            // Might change with LE/BE
-            end.push(v as u8);
-            end.push((v >> 8) as u8);
-        });
+    for (translation, entry) in translations.translations.iter().zip(result.entries.iter()) {
+        if entry.skipped.is_some() {
+            continue;
+        }
 
-    end
-}
+        let replaced = entry.count(Action::Replaced) + entry.count(Action::Overwritten) + entry.resource_replacements + entry.user_string_replacements;
+        println!("Replaced {} occurrences of {}", replaced, translation.original);
+        if entry.count(Action::Overwritten) > 0 {
+            println!("WARNING: {} takes fewer bytes than {}. Errors may happen.", translation.original, translation.translated);
+        }
+        if entry.count(Action::Relocated) > 0 {
+            println!("Relocating {} occurrences of {}", entry.count(Action::Relocated), translation.original);
+        }
+        if entry.count(Action::TooLong) > 0 {
+            println!("WARNING: {} takes fewer bytes than {}. Skipping {} occurrences.", translation.original, translation.translated, entry.count(Action::TooLong));
+        }
+        if entry.count(Action::Inconsistent) > 0 {
+            println!("WARNING: {} can't be replaced by {} in every architecture. Skipping {} occurrences.", translation.original, translation.translated, entry.count(Action::Inconsistent));
+        }
+        if entry.count(Action::NonAsciiCfString) > 0 {
+            println!("WARNING: {} is used as a CFString, which can only hold ASCII in a C string section. Skipping {} occurrences. Store it as UTF-16 in __ustring instead.", translation.original, entry.count(Action::NonAsciiCfString));
+        }
+        if entry.count(Action::PastSectionEnd) > 0 {
+            println!("WARNING: {} at the end of a section has no room for {}. Skipping {} occurrences.", translation.original, translation.translated, entry.count(Action::PastSectionEnd));
+        }
+    }
 
-fn translate(slice: &mut [u8], translations: &Vec<Translation>, potentially_harmful: bool) {
-    for translation in translations.iter() {
-        let original = string_to_utf16_vec(&translation.original);
-        let translated = string_to_utf16_vec(&translation.translated);
-
-        if original.len() < translated.len() {
-            if potentially_harmful {
-                println!("WARNING: {} takes fewer bytes than {}. Errors may happen.", translation.original, translation.translated);
-            } else {
-                println!("WARNING: {} takes fewer bytes than {}. Skipping this translation.", translation.original, translation.translated);
-                continue;
-            }
+    for section in result.sections.iter() {
+        println!("{}: {} replacements", section.name, section.replaced);
+    }
+    if result.cf_strings_updated > 0 {
+        println!("__cfstring: {} lengths updated", result.cf_strings_updated);
+    }
+    if result.relocated_strings > 0 {
+        println!("{}: {} relocated strings, {} references rewritten", translator::RELOCATION_SECTION_NAME, result.relocated_strings, result.rewritten_references);
+    }
+    if let Some(resources) = &result.resources {
+        println!("Resource string tables: {} replacements", resources.string_tables);
+        println!("Resource dialogs: {} replacements", resources.dialogs);
+        println!("Resource menus: {} replacements", resources.menus);
+        if let Some(section) = resources.moved_to {
+            println!("Resources moved to new section {}", section);
         }
+    }
+    if let Some(user_strings) = &result.user_strings {
+        println!("#US: {} replacements, {} ldstr rewritten", user_strings.replaced, user_strings.rewritten);
+        if let Some(section) = user_strings.moved_to {
+            println!("Metadata moved to new section {}", section);
+        }
+        if user_strings.strong_name_signed {
+            println!("WARNING: The assembly is strong name signed, the signature is no longer valid");
+        }
+    }
 
-        let replaced = replace_slice(slice, &original[..], &translated[..]);
+    for warning in result.warnings.iter() {
+        println!("WARNING: {}", warning);
+    }
 
-        println!("Replaced {} occurences of {}", replaced, translation.original);
+    if result.signature_stripped {
+        println!("Removed the signature of {}", exe_path);
+    } else if result.signed {
+        println!("WARNING: {} is signed. The signature won't match the translated executable, use --strip-signature to remove it.", exe_path);
+    }
+    if let Some(checksum) = result.checksum {
+        println!("Checksum updated to {:#010x}", checksum);
     }
-}
 
-fn replace_slice<T>(source: &mut [T], from: &[T], to: &[T]) -> usize
-where
-    T: Clone + PartialEq + Default,
-{
-    let mut number_replaced = 0;
-    
-    let end_offset = std::cmp::max(from.len(), to.len());
-
-    'outer: for i in 0 .. source.len()-end_offset+1 {
-        for j in 0 .. from.len() {
-            if source[i+j] != from[j] {
-                continue 'outer;
-            }
-        }
+    if matches.is_present("dry run") {
+        let mut previews: Vec<_> = result.entries.iter()
+            .enumerate()
+            .flat_map(|(i, entry)| entry.occurrences.iter().map(move |occurrence| (i, entry, occurrence)))
+            .collect();
+        previews.sort_by_key(|(_, _, occurrence)| occurrence.offset);
+
+        for (i, entry, occurrence) in previews {
+            let original_bytes = entry.original_bytes.unwrap_or(0);
+            let end = match occurrence.action {
+                Action::Replaced | Action::Overwritten => occurrence.offset + std::cmp::max(original_bytes, entry.translated_bytes.unwrap_or(0)),
+                _ => occurrence.offset + original_bytes,
+            };
 
-        for j in 0 .. from.len() {
-            source[i+j] = T::default();
+            println!();
+            println!("{} at {:#x} in {}: {:?}", translations.translations[i].original, occurrence.offset, occurrence.section, occurrence.action);
+            println!("Before:");
+            hexdump(patcher.original(), occurrence.offset, end);
+            println!("After:");
+            hexdump(patcher.patched(), occurrence.offset, end);
         }
 
-        for j in 0 .. to.len() {
-            source[i+j] = to[j].clone();
-        }
+        println!();
+        println!("Dry run, {} was not written", out_path);
+    } else if write_patch {
+        let patch = patcher.bps_patch();
+        write_file(out_path, &patch)?;
+        println!("Wrote a {} bytes patch to {}", patch.len(), out_path);
+    } else {
+        write_file(out_path, patcher.patched())?;
+    }
 
-        number_replaced += 1;
+    if let Some(report_path) = matches.value_of("report") {
+        Report::new(exe_path, translation_path, &translations, &result).write(report_path)?;
+        println!("Wrote report to {}", report_path);
     }
 
-    number_replaced
+    Ok(())
 }
 
-fn write_result(out_path: &str, exe_buf: &Vec<u8>) -> std::io::Result<()> {
-    let file = File::create(out_path)?;
-    let mut writer = BufWriter::new(file);
+fn apply_command(matches: &ArgMatches) -> Result<()> {
+    let exe_path = matches.value_of("EXE_FILE").unwrap();
+    let patch_path = matches.value_of("PATCH_FILE").unwrap();
+    let default_out_path = format!("{}.translated", exe_path);
+    let out_path = matches.value_of("OUT_FILE").unwrap_or(&default_out_path);
+
+    let exe_buf = read_file(exe_path)?;
+    let patch_buf = read_file(patch_path)?;
 
-    writer.write_all(&exe_buf[..])?;
+    let patched = patch::apply(&exe_buf, &patch_buf)?;
+    write_file(out_path, &patched)?;
+    println!("Patched {} into {}", exe_path, out_path);
 
     Ok(())
 }
 
-fn main() {
-    let matches = App::new("Translator")
-        .version("1.0")
-        .author("Flat Bartender <flat.bartender@gmail.com>")
-        .about("Finds strings in an exe files and replaces them with a translation")
-        .arg(Arg::with_name("EXE_FILE")
-            .help("The input executable file to be translated")
-            .required(true))
-        .arg(Arg::with_name("CSV_FILE")
-            .help("The input CSV file containing the translations. First column is original text, second column is translated text.")
-            .required(true))
-        .arg(Arg::with_name("OUT_FILE")
-             .help("The file to write the translated executable to. Leave blank for default (<exe name>.translated)")
-             .required(false))
-        .arg(Arg::with_name("potentially harmful")
-             .help("Sometimes, the original text may take fewer bytes than the translated text. Replacing those can be harmful. Use this to do it anyway.")
-             .required(false)
-             .short("p")
-             .long("potentially-harmful"))
-        .get_matches();
-
+fn extract_command(matches: &ArgMatches) -> Result<()> {
     let exe_path = matches.value_of("EXE_FILE").unwrap();
-    let csv_path = matches.value_of("CSV_FILE").unwrap();
-    let default_out_path = format!("{}.translated", exe_path);
-    let out_path = matches.value_of("OUT_FILE").unwrap_or(&default_out_path);
+    let default_template_path = format!("{}.csv", exe_path);
+    let template_path = matches.value_of("TEMPLATE_FILE").unwrap_or(&default_template_path);
+    let min_length = matches.value_of("min length").unwrap().parse::<usize>().unwrap();
+
+    let exe_buf = read_file(exe_path)?;
+    let mut warnings = Vec::new();
+    let strings = translator::extract_strings(&exe_buf, encoding(matches), &section_filter(matches), min_length, &mut warnings)?;
+    for warning in warnings.iter() {
+        println!("WARNING: {}", warning);
+    }
 
-    let mut exe_buf = load_exe(exe_path).unwrap();
-    let translations = load_translations(csv_path).unwrap();
-    
-    let pe_object = parse_pe_obj(&exe_buf).unwrap();
+    let lowercase_path = template_path.to_lowercase();
+    let written = if lowercase_path.ends_with(".pot") || lowercase_path.ends_with(".po") {
+        po::write_template(template_path, &strings).map_err(|error| Error::io(template_path, error))?
+    } else if lowercase_path.ends_with(".xlf") || lowercase_path.ends_with(".xliff") {
+        let version = match matches.value_of("xliff version").unwrap() {
+            "2.0" => xliff::Version::V2_0,
+            _ => xliff::Version::V1_2,
+        };
+        let language = matches.value_of("source language").unwrap();
+        xliff::write_template(template_path, exe_path, language, version, &strings).map_err(|error| Error::io(template_path, error))?
+    } else {
+        extract::write_template(template_path, &strings).map_err(|error| Error::csv(template_path, error))?
+    };
+
+    println!("Found {} strings, wrote {} unique strings to {}", strings.len(), written, template_path);
 
-    pe_object.sections.iter().for_each(|section| {
-        let ptr = section.pointer_to_raw_data as usize;
-        let size = section.size_of_raw_data as usize;
+    Ok(())
+}
 
-        if section.name().unwrap() == ".rdata" {
-            translate(&mut exe_buf[ptr .. ptr + size], &translations, matches.is_present("potentially harmful"));
-        }
+fn section_filter(matches: &ArgMatches) -> SectionFilter {
+    if matches.is_present("all data sections") {
+        SectionFilter::InitializedData
+    } else if let Some(names) = matches.values_of("section") {
+        SectionFilter::Named(names.map(String::from).collect())
+    } else {
+        SectionFilter::ReadOnlyData
+    }
+}
+
+fn section_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("section")
+            .help("A section to search for strings, as segment,section in Mach-O files. Can be repeated. Defaults to .rdata in PE files, .rodata in ELF files and __TEXT,__cstring in Mach-O files")
+            .required(false)
+            .short("s")
+            .long("section")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("all data sections")
+            .help("Search every section containing initialized data")
+            .required(false)
+            .short("a")
+            .long("all-data-sections")
+            .conflicts_with("section"),
+    ]
+}
+
+fn encoding_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("encoding")
+        .help("The encoding the strings are stored with in the executable. Defaults to utf-16le for PE files and utf-8 otherwise")
+        .required(false)
+        .short("e")
+        .long("encoding")
+        .takes_value(true)
+        .possible_values(Encoding::NAMES)
+        .case_insensitive(true)
+}
+
+const EXIT_CODES: &str = "EXIT CODES:
+    0    Success
+    1    Invalid arguments
+    2    A file couldn't be read or written
+    3    The translation file is invalid
+    4    The executable is malformed
+    5    The file isn't a PE, ELF or Mach-O executable
+    6    An option isn't supported for this kind of executable
+    7    The patch is invalid or was made for another file
+    8    A text can't be represented in its encoding
+    9    A structure of the executable goes past the end of the file";
+
+fn is_number(value: String) -> std::result::Result<(), String> {
+    value.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("{} isn't a number", value))
+}
+
+/// `translator EXE_FILE CSV_FILE [OUT_FILE]` predates the subcommands, so arguments that don't
+/// start with one are given to translate
+fn arguments() -> Vec<OsString> {
+    let mut arguments: Vec<OsString> = env::args_os().collect();
+
+    let starts_with_subcommand = arguments.get(1).is_none_or(|first| {
+        let first = first.to_string_lossy();
+        ["translate", "apply", "extract", "help", "-h", "--help", "-V", "--version"].contains(&first.as_ref())
     });
+    if !starts_with_subcommand {
+        arguments.insert(1, OsString::from("translate"));
+    }
 
-    write_result(&out_path, &exe_buf).unwrap();
+    arguments
+}
+
+fn main() {
+    let matches = App::new("Translator")
+        .version("1.0")
+        .author("Flat Bartender <flat.bartender@gmail.com>")
+        .about("Finds strings in PE, ELF or Mach-O executables and replaces them with a translation")
+        .usage("translator [translate] [OPTIONS] <EXE_FILE> <TRANSLATION_FILE> [OUT_FILE]\n    translator <SUBCOMMAND>")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help(EXIT_CODES)
+        .subcommand(SubCommand::with_name("translate")
+            .about("Replaces the strings of an exe file with the translations of a CSV, PO or XLIFF file")
+            .arg(Arg::with_name("EXE_FILE")
+                .help("The input executable file to be translated")
+                .required(true))
+            .arg(Arg::with_name("TRANSLATION_FILE")
+                .help("The input CSV, PO or XLIFF file containing the translations. In CSV files, first column is original text, second column is translated text, optional third column is the encoding of that line, unless --header is used. In PO files, msgctxt limits an entry to the section of that name, an encoding=NAME flag gives its encoding, and fuzzy entries are skipped. In XLIFF files, units that aren't approved are skipped.")
+                .required(true))
+            .arg(Arg::with_name("OUT_FILE")
+                 .help("The file to write the translated executable to. Leave blank for default (<exe name>.translated)")
+                 .required(false))
+            .arg(Arg::with_name("potentially harmful")
+                 .help("Sometimes, the original text may take fewer bytes than the translated text. Replacing those can be harmful. Use this to do it anyway.")
+                 .required(false)
+                 .short("p")
+                 .long("potentially-harmful"))
+            .arg(Arg::with_name("relocate")
+                 .help("Instead of skipping or overwriting translations that take more bytes than the original text, copy them to a new section and rewrite the absolute references to the original text.")
+                 .required(false)
+                 .short("r")
+                 .long("relocate")
+                 .conflicts_with("potentially harmful"))
+            .arg(Arg::with_name("slack alignment")
+                 .help("Translations may use the zeroes following their original text, up to the next multiple of this many bytes, since compilers pad strings to align the next one. Use 1 to disable.")
+                 .required(false)
+                 .long("slack-alignment")
+                 .takes_value(true)
+                 .validator(is_number)
+                 .default_value("4"))
+            .arg(Arg::with_name("allow substrings")
+                 .help("Also replace original text found at the end of longer strings. By default, only whole strings are replaced.")
+                 .required(false)
+                 .long("allow-substrings"))
+            .arg(Arg::with_name("resources")
+                 .help("Also translate the string tables, dialogs and menus in the resources. Translations of any length are allowed there.")
+                 .required(false)
+                 .short("R")
+                 .long("resources"))
+            .arg(Arg::with_name("dotnet")
+                 .help("Also translate the user strings of a .NET assembly, which are loaded by ldstr. Translations of any length are allowed there.")
+                 .required(false)
+                 .long("dotnet"))
+            .arg(Arg::with_name("dry run")
+                 .help("Show what would be replaced, with the bytes around each occurrence before and after, without writing OUT_FILE.")
+                 .required(false)
+                 .short("n")
+                 .long("dry-run"))
+            .arg(Arg::with_name("output format")
+                 .help("What to write to OUT_FILE: the translated executable, or a BPS patch turning the original executable into it, which can be distributed instead. The default OUT_FILE for patches is <exe name>.bps")
+                 .required(false)
+                 .short("f")
+                 .long("output-format")
+                 .takes_value(true)
+                 .possible_values(&["exe", "bps"])
+                 .default_value("exe"))
+            .arg(Arg::with_name("strip signature")
+                 .help("Remove the Authenticode signature of PE files, which translating invalidates, so that the output looks unsigned rather than tampered with.")
+                 .required(false)
+                 .long("strip-signature"))
+            .arg(Arg::with_name("checksum")
+                 .help("When to recompute the checksum of PE files, which drivers and some protected executables need to be right. By default, it is only recomputed if the original executable has one.")
+                 .required(false)
+                 .long("checksum")
+                 .takes_value(true)
+                 .possible_values(&["always", "if-present", "never"])
+                 .default_value("if-present"))
+            .arg(Arg::with_name("report")
+                 .help("Write a JSON report of every translation, where it was replaced, and why it wasn't, to this file.")
+                 .required(false)
+                 .long("report")
+                 .value_name("REPORT_FILE")
+                 .takes_value(true))
+            .arg(Arg::with_name("header")
+                 .help("The first row of the CSV file names its columns, which can then be in any order. Columns other than the original text, translated text and encoding are ignored, so they can hold notes or context.")
+                 .required(false)
+                 .long("header"))
+            .arg(Arg::with_name("original column")
+                 .help("The name of the original text column, with --header")
+                 .required(false)
+                 .long("original-column")
+                 .value_name("NAME")
+                 .takes_value(true)
+                 .default_value("original"))
+            .arg(Arg::with_name("translated column")
+                 .help("The name of the translated text column, with --header")
+                 .required(false)
+                 .long("translated-column")
+                 .value_name("NAME")
+                 .takes_value(true)
+                 .default_value("translated"))
+            .arg(Arg::with_name("encoding column")
+                 .help("The name of the optional encoding column, with --header")
+                 .required(false)
+                 .long("encoding-column")
+                 .value_name("NAME")
+                 .takes_value(true)
+                 .default_value("encoding"))
+            .arg(encoding_arg())
+            .args(&section_args()))
+        .subcommand(SubCommand::with_name("apply")
+            .about("Applies a BPS patch made by translate, checking that the input is the file the patch was made for")
+            .arg(Arg::with_name("EXE_FILE")
+                .help("The original executable file")
+                .required(true))
+            .arg(Arg::with_name("PATCH_FILE")
+                .help("The BPS patch to apply")
+                .required(true))
+            .arg(Arg::with_name("OUT_FILE")
+                 .help("The file to write the patched executable to. Leave blank for default (<exe name>.translated)")
+                 .required(false)))
+        .subcommand(SubCommand::with_name("extract")
+            .about("Dumps the strings of an exe file into a CSV, POT or XLIFF template to be filled by translators")
+            .arg(Arg::with_name("EXE_FILE")
+                .help("The input executable file to extract the strings from")
+                .required(true))
+            .arg(Arg::with_name("TEMPLATE_FILE")
+                .help("The file to write the strings to: a POT template if it ends with .pot, with the section and address of each string as references, an XLIFF file if it ends with .xlf or .xliff, with the location and byte budget of each string, a CSV file otherwise. Leave blank for default (<exe name>.csv)")
+                .required(false))
+            .arg(Arg::with_name("min length")
+                 .help("Strings shorter than this many characters are ignored, as they are usually binary data that happens to look like text.")
+                 .required(false)
+                 .short("m")
+                 .long("min-length")
+                 .takes_value(true)
+                 .validator(is_number)
+                 .default_value("3"))
+            .arg(Arg::with_name("xliff version")
+                 .help("The version of XLIFF to write when TEMPLATE_FILE ends with .xlf or .xliff")
+                 .required(false)
+                 .long("xliff-version")
+                 .takes_value(true)
+                 .possible_values(&["1.2", "2.0"])
+                 .default_value("1.2"))
+            .arg(Arg::with_name("source language")
+                 .help("The language of the strings, written in XLIFF files")
+                 .required(false)
+                 .long("source-language")
+                 .takes_value(true)
+                 .default_value("en"))
+            .arg(encoding_arg())
+            .args(&section_args()))
+        .get_matches_from(arguments());
+
+    let result = match matches.subcommand() {
+        ("translate", Some(sub_matches)) => translate_command(sub_matches),
+        ("apply", Some(sub_matches)) => apply_command(sub_matches),
+        ("extract", Some(sub_matches)) => extract_command(sub_matches),
+        _ => unreachable!(),
+    };
+
+    if let Err(error) = result {
+        eprintln!("ERROR: {}", error);
+        std::process::exit(error.exit_code());
+    }
 }
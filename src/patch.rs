@@ -0,0 +1,352 @@
+//! BPS patches, which can be distributed instead of the translated executable since they only
+//! hold the bytes that changed.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crc32fast::hash as crc32;
+
+const MAGIC: &[u8] = b"BPS1";
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+/// Shorter runs of identical bytes are cheaper to store as they are
+const MIN_MATCH: usize = 8;
+/// Size of the blocks of the source that moved data is looked up by
+const BLOCK_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub enum PatchError {
+    NotBps,
+    Truncated,
+    /// The patch was made from another file
+    WrongSource { expected: u32, actual: u32 },
+    /// The patch was made from a file of another size
+    WrongSourceSize { expected: usize, actual: usize },
+    /// The patch is corrupted
+    PatchChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::NotBps => write!(f, "Not a BPS patch"),
+            PatchError::Truncated => write!(f, "The patch is truncated or malformed"),
+            PatchError::WrongSource { expected, actual } => write!(f, "The patch was made for another file: expected CRC32 {:08x}, found {:08x}", expected, actual),
+            PatchError::WrongSourceSize { expected, actual } => write!(f, "The patch was made for another file: expected {} bytes, found {}", expected, actual),
+            PatchError::PatchChecksum { expected, actual } => write!(f, "The patch is corrupted: expected CRC32 {:08x}, found {:08x}", expected, actual),
+            PatchError::TargetChecksum { expected, actual } => write!(f, "The patched file is wrong: expected CRC32 {:08x}, found {:08x}", expected, actual),
+        }
+    }
+}
+
+fn write_number(buf: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let low = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(0x80 | low);
+            return;
+        }
+        buf.push(low);
+        value -= 1;
+    }
+}
+
+fn write_action(buf: &mut Vec<u8>, action: usize, length: usize) {
+    write_number(buf, (length - 1) << 2 | action);
+}
+
+fn matching(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+struct Encoder<'a> {
+    source: &'a [u8],
+    target: &'a [u8],
+    patch: Vec<u8>,
+    /// Where the last source copy ended, which the next one is relative to
+    source_offset: usize,
+    /// Offset of the first source block with each content, built the first time it's needed
+    blocks: Option<HashMap<&'a [u8], usize>>,
+}
+
+impl<'a> Encoder<'a> {
+    /// Finds `target[offset..]` somewhere else in the source. Data moved by adding a section,
+    /// like the overlay, usually follows the previous copy.
+    fn find_moved(&mut self, offset: usize) -> Option<(usize, usize)> {
+        let target = &self.target[offset ..];
+
+        let length = matching(target, self.source.get(self.source_offset ..)?);
+        if length >= MIN_MATCH {
+            return Some((self.source_offset, length));
+        }
+
+        if target.len() < BLOCK_SIZE {
+            return None;
+        }
+
+        let source = self.source;
+        let blocks = self.blocks.get_or_insert_with(|| {
+            let mut blocks = HashMap::new();
+            for (i, block) in source.chunks_exact(BLOCK_SIZE).enumerate() {
+                blocks.entry(block).or_insert(i * BLOCK_SIZE);
+            }
+            blocks
+        });
+
+        let start = *blocks.get(&target[.. BLOCK_SIZE])?;
+        Some((start, matching(target, &source[start ..])))
+    }
+
+    fn flush_literal(&mut self, start: Option<usize>, end: usize) {
+        if let Some(start) = start {
+            write_action(&mut self.patch, TARGET_READ, end - start);
+            self.patch.extend_from_slice(&self.target[start .. end]);
+        }
+    }
+
+    fn encode(mut self) -> Vec<u8> {
+        let mut literal_start = None;
+        let mut offset = 0;
+
+        while offset < self.target.len() {
+            let unchanged = matching(&self.target[offset ..], self.source.get(offset ..).unwrap_or(&[]));
+            if unchanged >= MIN_MATCH || (unchanged > 0 && offset + unchanged == self.target.len()) {
+                self.flush_literal(literal_start.take(), offset);
+                write_action(&mut self.patch, SOURCE_READ, unchanged);
+                offset += unchanged;
+                continue;
+            }
+
+            if let Some((start, length)) = self.find_moved(offset).filter(|&(_, length)| length >= MIN_MATCH) {
+                self.flush_literal(literal_start.take(), offset);
+                write_action(&mut self.patch, SOURCE_COPY, length);
+                let relative = start as i64 - self.source_offset as i64;
+                write_number(&mut self.patch, (relative.unsigned_abs() as usize) << 1 | (relative < 0) as usize);
+                self.source_offset = start + length;
+                offset += length;
+                continue;
+            }
+
+            literal_start.get_or_insert(offset);
+            offset += 1;
+        }
+        self.flush_literal(literal_start, offset);
+
+        self.patch
+    }
+}
+
+/// Creates a patch turning `source` into `target`.
+pub fn create(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = MAGIC.to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    // No metadata
+    write_number(&mut patch, 0);
+
+    let mut patch = Encoder {
+        source,
+        target,
+        patch,
+        source_offset: 0,
+        blocks: None,
+    }.encode();
+
+    patch.extend_from_slice(&crc32(source).to_le_bytes());
+    patch.extend_from_slice(&crc32(target).to_le_bytes());
+    let patch_crc = crc32(&patch);
+    patch.extend_from_slice(&patch_crc.to_le_bytes());
+
+    patch
+}
+
+struct Decoder<'a> {
+    patch: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn read_bytes(&mut self, size: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self.patch.get(self.offset .. self.offset.checked_add(size).ok_or(PatchError::Truncated)?)
+            .ok_or(PatchError::Truncated)?;
+        self.offset += size;
+        Ok(bytes)
+    }
+
+    fn read_number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+
+        loop {
+            let byte = self.read_bytes(1)?[0];
+            value = ((byte & 0x7f) as usize).checked_mul(shift)
+                .and_then(|low| value.checked_add(low))
+                .ok_or(PatchError::Truncated)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).filter(|&shift| shift != 0).ok_or(PatchError::Truncated)?;
+            value = value.checked_add(shift).ok_or(PatchError::Truncated)?;
+        }
+    }
+
+    /// Moves `position` by a signed relative offset
+    fn read_relative(&mut self, position: usize) -> Result<usize, PatchError> {
+        let number = self.read_number()?;
+        let distance = number >> 1;
+
+        if number & 1 != 0 {
+            position.checked_sub(distance)
+        } else {
+            position.checked_add(distance)
+        }.ok_or(PatchError::Truncated)
+    }
+}
+
+fn read_crc(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Applies a patch to `source`, checking that it is the file the patch was made from and that
+/// the result is the intended one.
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < MAGIC.len() + 12 || &patch[.. MAGIC.len()] != MAGIC {
+        return Err(PatchError::NotBps);
+    }
+
+    let footer = patch.len() - 12;
+    let expected = read_crc(&patch[footer + 8 ..]);
+    let actual = crc32(&patch[.. footer + 8]);
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+
+    let mut decoder = Decoder {
+        patch: &patch[.. footer],
+        offset: MAGIC.len(),
+    };
+    let source_size = decoder.read_number()?;
+    let target_size = decoder.read_number()?;
+    let metadata_size = decoder.read_number()?;
+    decoder.read_bytes(metadata_size)?;
+
+    if source_size != source.len() {
+        return Err(PatchError::WrongSourceSize { expected: source_size, actual: source.len() });
+    }
+
+    let expected = read_crc(&patch[footer ..]);
+    let actual = crc32(source);
+    if expected != actual {
+        return Err(PatchError::WrongSource { expected, actual });
+    }
+
+    // The size comes from the patch, so it is only trusted as far as the patch and source can
+    // make a file that large without repeating themselves
+    let mut target: Vec<u8> = Vec::with_capacity(std::cmp::min(target_size, source.len() + patch.len()));
+    let mut source_offset = 0;
+    let mut target_offset = 0;
+    while decoder.offset < footer {
+        let action = decoder.read_number()?;
+        let length = (action >> 2).checked_add(1).ok_or(PatchError::Truncated)?;
+        let start = target.len();
+        if start.checked_add(length).is_none_or(|end| end > target_size) {
+            return Err(PatchError::Truncated);
+        }
+
+        match action & 3 {
+            SOURCE_READ => target.extend_from_slice(source.get(start .. start + length).ok_or(PatchError::Truncated)?),
+            TARGET_READ => target.extend_from_slice(decoder.read_bytes(length)?),
+            SOURCE_COPY => {
+                source_offset = decoder.read_relative(source_offset)?;
+                let end = source_offset.checked_add(length).ok_or(PatchError::Truncated)?;
+                target.extend_from_slice(source.get(source_offset .. end).ok_or(PatchError::Truncated)?);
+                source_offset = end;
+            },
+            TARGET_COPY => {
+                target_offset = decoder.read_relative(target_offset)?;
+                if target_offset >= start {
+                    return Err(PatchError::Truncated);
+                }
+                // The copy may overlap what it writes, to repeat a pattern
+                for _ in 0 .. length {
+                    target.push(target[target_offset]);
+                    target_offset += 1;
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    let expected = read_crc(&patch[footer + 4 ..]);
+    let actual = crc32(&target);
+    if target.len() != target_size || expected != actual {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that don't repeat, so that only the moved data can be found elsewhere
+    fn noise(size: usize, mut state: u32) -> Vec<u8> {
+        (0 .. size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// A translated file: some bytes changed in place, and the overlay moved after a new
+    /// section
+    fn translated(source: &[u8]) -> Vec<u8> {
+        let mut target = source[.. 3072].to_vec();
+        target[100 .. 110].copy_from_slice(b"Translated");
+        target.extend_from_slice(&noise(512, 7));
+        target.extend_from_slice(&source[3072 ..]);
+        target
+    }
+
+    #[test]
+    fn round_trips() {
+        let source = noise(4096, 1);
+        let target = translated(&source);
+
+        let patch = create(&source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+        // The overlay is copied from the source rather than stored in the patch
+        assert!(patch.len() < 512 + 256, "{} bytes", patch.len());
+    }
+
+    #[test]
+    fn rejects_another_source() {
+        let source = noise(4096, 1);
+        let patch = create(&source, &translated(&source));
+
+        let mut modified = source.clone();
+        modified[2000] ^= 1;
+        assert!(matches!(apply(&modified, &patch), Err(PatchError::WrongSource { .. })));
+
+        assert!(matches!(apply(&source[.. 4000], &patch), Err(PatchError::WrongSourceSize { expected: 4096, actual: 4000 })));
+    }
+
+    #[test]
+    fn rejects_a_corrupted_patch() {
+        let source = noise(4096, 1);
+        let mut patch = create(&source, &translated(&source));
+
+        let middle = patch.len() / 2;
+        patch[middle] ^= 1;
+        assert!(matches!(apply(&source, &patch), Err(PatchError::PatchChecksum { .. })));
+    }
+}
@@ -0,0 +1,364 @@
+//! Translating a whole executable: the selected sections first, then what only exists in PE
+//! files.
+
+use serde::Serialize;
+
+use crate::encoding::Encoding;
+use crate::error::{Error, Result};
+use crate::mach;
+use crate::matcher::{Action, Matcher, OverflowPolicy, SectionResult};
+use crate::patch;
+use crate::pe;
+use crate::relocate::{self, Overflow};
+use crate::{check_sections, parse_binary, select_sections, Binary, SectionFilter, TranslationSet};
+
+pub use crate::dotnet::UserStringCounts;
+pub use crate::resources::ResourceCounts;
+
+/// When to recompute the checksum of PE files
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChecksumPolicy {
+    Always,
+    /// Only if the original executable has one, since most loaders ignore it
+    IfPresent,
+    Never,
+}
+
+pub struct Options {
+    /// Defaults to the usual encoding of the executable format
+    pub encoding: Option<Encoding>,
+    pub sections: SectionFilter,
+    pub overflow_policy: OverflowPolicy,
+    /// Also replace original text found at the end of longer strings
+    pub allow_substrings: bool,
+    /// Translations may use the zeroes after their original text up to the next multiple of
+    /// this many bytes
+    pub slack_alignment: usize,
+    /// Also translate the string tables, dialogs and menus of PE files
+    pub resources: bool,
+    /// Also translate the user strings of .NET assemblies
+    pub dotnet: bool,
+    /// Remove the Authenticode signature of PE files
+    pub strip_signature: bool,
+    pub checksum: ChecksumPolicy,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            encoding: None,
+            sections: SectionFilter::ReadOnlyData,
+            overflow_policy: OverflowPolicy::Skip,
+            allow_substrings: false,
+            slack_alignment: 4,
+            resources: false,
+            dotnet: false,
+            strip_signature: false,
+            checksum: ChecksumPolicy::IfPresent,
+        }
+    }
+}
+
+/// An occurrence of an original text
+#[derive(Serialize)]
+pub struct OccurrenceResult {
+    pub section: String,
+    pub offset: usize,
+    /// RVA in PE files, virtual address otherwise
+    pub address: u64,
+    pub action: Action,
+}
+
+/// What happened to one translation of the set
+pub struct EntryResult {
+    pub encoding: Encoding,
+    /// Size of the encoded texts, unless the translation was skipped
+    pub original_bytes: Option<usize>,
+    pub translated_bytes: Option<usize>,
+    /// Why the translation couldn't be used at all
+    pub skipped: Option<String>,
+    pub occurrences: Vec<OccurrenceResult>,
+    /// Occurrences left out because they overlap another one
+    pub conflicts: usize,
+    /// Strings replaced in the string tables, dialogs and menus
+    pub resource_replacements: usize,
+    /// .NET user strings replaced
+    pub user_string_replacements: usize,
+}
+
+impl EntryResult {
+    pub fn count(&self, action: Action) -> usize {
+        self.occurrences.iter().filter(|occurrence| occurrence.action == action).count()
+    }
+}
+
+/// An occurrence that wasn't replaced because it overlaps one found before it. Translations
+/// are given by their index in the set.
+pub struct ConflictResult {
+    pub section: String,
+    pub offset: usize,
+    pub translation: usize,
+    pub kept_offset: usize,
+    pub kept_translation: usize,
+}
+
+pub struct SectionSummary {
+    pub name: String,
+    /// Occurrences replaced in place
+    pub replaced: usize,
+}
+
+/// Everything a translation did, for the caller to report
+pub struct PatchResult {
+    /// One per translation of the set, in the same order
+    pub entries: Vec<EntryResult>,
+    pub conflicts: Vec<ConflictResult>,
+    pub sections: Vec<SectionSummary>,
+    /// Mach-O CFStrings whose length was rewritten
+    pub cf_strings_updated: usize,
+    pub relocated_strings: usize,
+    pub rewritten_references: usize,
+    pub resources: Option<ResourceCounts>,
+    pub user_strings: Option<UserStringCounts>,
+    /// The original executable has an Authenticode signature
+    pub signed: bool,
+    pub signature_stripped: bool,
+    /// The new checksum, if it was recomputed
+    pub checksum: Option<u32>,
+    pub warnings: Vec<String>,
+}
+
+/// Translates an executable held in memory, keeping the original around for patches.
+pub struct Patcher {
+    original: Vec<u8>,
+    patched: Vec<u8>,
+}
+
+impl Patcher {
+    pub fn new(exe_buf: Vec<u8>) -> Patcher {
+        Patcher {
+            patched: exe_buf.clone(),
+            original: exe_buf,
+        }
+    }
+
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    pub fn patched(&self) -> &[u8] {
+        &self.patched
+    }
+
+    pub fn into_patched(self) -> Vec<u8> {
+        self.patched
+    }
+
+    /// A BPS patch turning the original executable into the translated one
+    pub fn bps_patch(&self) -> Vec<u8> {
+        patch::create(&self.original, &self.patched)
+    }
+
+    /// Translates the original executable. Translating again starts over from the original.
+    pub fn translate(&mut self, translations: &TranslationSet, options: &Options) -> Result<PatchResult> {
+        let Patcher { original, patched } = self;
+        patched.clear();
+        patched.extend_from_slice(original);
+
+        let mut warnings = Vec::new();
+        let binary = parse_binary(original)?;
+        let sections = check_sections(select_sections(&binary, &options.sections), original.len(), &mut warnings);
+        let encoding = options.encoding.unwrap_or_else(|| binary.default_encoding());
+        let is_pe = matches!(binary, Binary::Pe(_));
+        let cf_strings = match &binary {
+            Binary::Mach(slices) => mach::cf_strings(original, slices, &mut warnings),
+            _ => Vec::new(),
+        };
+
+        let pe_only = [
+            (options.overflow_policy == OverflowPolicy::Relocate, "Relocating translations"),
+            (options.resources, "Translating resources"),
+            (options.dotnet, "Translating .NET user strings"),
+        ];
+        for &(enabled, feature) in pe_only.iter() {
+            if enabled && !is_pe {
+                return Err(Error::Unsupported(format!("{} is only supported in PE files", feature)));
+            }
+        }
+
+        if sections.is_empty() {
+            warnings.push("None of the selected sections exist in the executable".to_string());
+        }
+
+        let signed = is_pe && pe::Image::parse(original)?.certificate_table(original).is_some();
+
+        let matcher = Matcher::new(&translations.translations, encoding, options.overflow_policy, options.allow_substrings, options.slack_alignment)?;
+
+        let mut entries: Vec<EntryResult> = translations.translations.iter()
+            .map(|translation| EntryResult {
+                encoding: translation.encoding.unwrap_or(encoding),
+                original_bytes: None,
+                translated_bytes: None,
+                skipped: None,
+                occurrences: Vec::new(),
+                conflicts: 0,
+                resource_replacements: 0,
+                user_string_replacements: 0,
+            })
+            .collect();
+        for entry in matcher.entries().iter() {
+            entries[entry.index].original_bytes = Some(entry.original.len());
+            entries[entry.index].translated_bytes = Some(entry.translated.len());
+        }
+        for skipped in matcher.skipped().iter() {
+            entries[skipped.index].skipped = Some(skipped.reason.clone());
+        }
+
+        // Addresses pointed to by relocations or CFStrings can't be used as slack
+        let mut references: Vec<usize> = if is_pe {
+            let image = pe::Image::parse(original)?;
+            image.relocations(original).iter()
+                .filter_map(|relocation| image.rva_to_offset(image.read_pointer(original, relocation) as u32))
+                .collect()
+        } else {
+            mach::references(&cf_strings)
+        };
+        references.sort();
+
+        let mut results: Vec<SectionResult> = sections.iter()
+            .map(|section| {
+                let range = section.range();
+                let section_references: Vec<usize> = references.iter()
+                    .filter(|&offset| range.contains(offset))
+                    .map(|offset| offset - range.start)
+                    .collect();
+
+                matcher.locate(&original[range], &section.name, &section_references)
+            })
+            .collect();
+
+        mach::skip_non_ascii(&cf_strings, &sections, &mut results, matcher.entries());
+        // Every architecture of a fat binary has to be translated the same way
+        mach::skip_inconsistent(&sections, &mut results, matcher.entries().len());
+
+        let mut conflicts = Vec::new();
+        let mut section_summaries = Vec::new();
+        let mut overflows = Vec::new();
+        let mut cf_replaced = Vec::new();
+
+        for (section, result) in sections.iter().zip(results) {
+            matcher.apply(&mut patched[section.range()], &result);
+
+            for conflict in result.conflicts.iter() {
+                let translation = matcher.entries()[conflict.entry].index;
+                entries[translation].conflicts += 1;
+                conflicts.push(ConflictResult {
+                    section: section.label(),
+                    offset: section.offset + conflict.offset,
+                    translation,
+                    kept_offset: section.offset + conflict.kept_offset,
+                    kept_translation: matcher.entries()[conflict.kept_entry].index,
+                });
+            }
+
+            let mut replaced = 0;
+            for occurrence in result.occurrences.iter() {
+                let entry = &matcher.entries()[occurrence.entry];
+                let offset = section.offset + occurrence.offset;
+
+                entries[entry.index].occurrences.push(OccurrenceResult {
+                    section: section.label(),
+                    offset,
+                    address: section.address + occurrence.offset as u64,
+                    action: occurrence.action,
+                });
+
+                match occurrence.action {
+                    Action::Replaced | Action::Overwritten => {
+                        replaced += 1;
+
+                        if !cf_strings.is_empty() {
+                            cf_replaced.push((offset, entry));
+                        }
+                    },
+                    Action::Relocated => overflows.push(Overflow {
+                        offset,
+                        original: entry.translation.original.clone(),
+                        translated: entry.translated.clone(),
+                    }),
+                    _ => (),
+                }
+            }
+            section_summaries.push(SectionSummary {
+                name: section.label(),
+                replaced,
+            });
+        }
+
+        let cf_strings_updated = if cf_replaced.is_empty() {
+            0
+        } else {
+            mach::update_cf_strings(patched, &cf_strings, &cf_replaced)
+        };
+
+        let rewritten_references = if overflows.is_empty() {
+            0
+        } else {
+            relocate::relocate(patched, &overflows, &mut warnings)?
+        };
+
+        let resources = if options.resources {
+            Some(crate::resources::translate_resources(patched, &translations.translations, &mut warnings)?)
+        } else {
+            None
+        };
+
+        let user_strings = if options.dotnet {
+            Some(crate::dotnet::translate_user_strings(patched, &translations.translations, &mut warnings)?)
+        } else {
+            None
+        };
+
+        for (entry, &replaced) in entries.iter_mut().zip(resources.iter().flat_map(|counts| counts.translations.iter())) {
+            entry.resource_replacements = replaced;
+        }
+        for (entry, &replaced) in entries.iter_mut().zip(user_strings.iter().flat_map(|counts| counts.translations.iter())) {
+            entry.user_string_replacements = replaced;
+        }
+
+        let mut signature_stripped = false;
+        let mut checksum = None;
+        if is_pe {
+            let image = pe::Image::parse(patched)?;
+
+            if signed && options.strip_signature {
+                signature_stripped = image.strip_signature(patched);
+            }
+
+            let update = match options.checksum {
+                ChecksumPolicy::Always => true,
+                // Translating never changes the checksum field
+                ChecksumPolicy::IfPresent => image.checksum(patched) != 0,
+                ChecksumPolicy::Never => false,
+            };
+            if update {
+                checksum = Some(image.update_checksum(patched));
+            }
+        }
+
+        Ok(PatchResult {
+            entries,
+            conflicts,
+            sections: section_summaries,
+            cf_strings_updated,
+            relocated_strings: overflows.len(),
+            rewritten_references,
+            resources,
+            user_strings,
+            signed,
+            signature_stripped,
+            checksum,
+            warnings,
+        })
+    }
+}
@@ -0,0 +1,472 @@
+//! Raw access to the PE headers, for the fields goblin parses but doesn't let us modify.
+
+use goblin::pe::section_table::SectionTable;
+
+use crate::error::{Error, Result};
+
+pub const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+
+const SECTION_HEADER_SIZE: usize = 40;
+/// Images never have more, whatever NumberOfRvaAndSizes claims
+const MAX_DATA_DIRECTORIES: usize = 16;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset .. offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset .. offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset .. offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset .. offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset .. offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Section names are at most 8 bytes in the header, longer ones are in the string table. Names
+/// that aren't valid UTF-8 are kept as close as possible rather than rejected.
+pub fn section_name(section: &SectionTable) -> String {
+    match section.name() {
+        Ok(name) => name.to_string(),
+        Err(_) => String::from_utf8_lossy(&section.name).trim_end_matches('\0').to_string(),
+    }
+}
+
+/// Rounds `value` up to a multiple of `alignment`, or down if that doesn't fit in a u32.
+pub fn align_up(value: u32, alignment: u32) -> u32 {
+    if alignment == 0 {
+        return value;
+    }
+
+    value.checked_add(alignment - 1).unwrap_or(value) / alignment * alignment
+}
+
+/// The PE checksum: the file summed as 16-bit words with the carries folded back in, plus the
+/// file size. The checksum field must be zeroed.
+fn compute_checksum(exe_buf: &[u8]) -> u32 {
+    let mut sum: u64 = 0;
+
+    for word in exe_buf.chunks(2) {
+        let word = if word.len() == 2 { read_u16(word, 0) } else { word[0] as u16 };
+        sum += word as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+
+    (sum as u32).wrapping_add(exe_buf.len() as u32)
+}
+
+/// An absolute pointer the loader fixes up, found in the base relocation table.
+pub struct Relocation {
+    /// File offset of the pointer
+    pub offset: usize,
+    /// Size of the pointer in bytes, 4 or 8
+    pub size: usize,
+}
+
+/// The parts of a PE image needed to modify its layout.
+pub struct Image {
+    pub image_base: u64,
+    pub sections: Vec<SectionTable>,
+    optional_header: usize,
+    data_directories: usize,
+    number_of_data_directories: usize,
+    section_table: usize,
+}
+
+impl Image {
+    pub fn parse(exe_buf: &[u8]) -> Result<Image> {
+        let pe = goblin::pe::PE::parse(exe_buf)?;
+
+        let pe_pointer = read_u32(exe_buf, 0x3c) as usize;
+        let coff_header = pe_pointer + 4;
+        let optional_header = coff_header + 20;
+        let size_of_optional_header = read_u16(exe_buf, coff_header + 16) as usize;
+        let data_directories = optional_header + if pe.is_64 { 112 } else { 96 };
+        if data_directories > exe_buf.len() {
+            return Err(Error::out_of_bounds("Optional header", optional_header, data_directories - optional_header));
+        }
+
+        // Data directories that aren't in the file are treated as missing
+        let number_of_data_directories = std::cmp::min(read_u32(exe_buf, data_directories - 4) as usize, MAX_DATA_DIRECTORIES);
+        let number_of_data_directories = std::cmp::min(number_of_data_directories, (exe_buf.len() - data_directories) / 8);
+
+        Ok(Image {
+            image_base: pe.image_base as u64,
+            sections: pe.sections,
+            optional_header,
+            data_directories,
+            number_of_data_directories,
+            section_table: optional_header + size_of_optional_header,
+        })
+    }
+
+    pub fn section_alignment(&self, exe_buf: &[u8]) -> u32 {
+        read_u32(exe_buf, self.optional_header + 32)
+    }
+
+    pub fn file_alignment(&self, exe_buf: &[u8]) -> u32 {
+        read_u32(exe_buf, self.optional_header + 36)
+    }
+
+    /// Returns the file offset and size of the Authenticode certificate table, if the image is
+    /// signed.
+    pub fn certificate_table(&self, exe_buf: &[u8]) -> Option<(usize, usize)> {
+        self.data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_SECURITY)
+            .map(|(offset, size)| (offset as usize, size as usize))
+    }
+
+    /// Removes the certificate table so that the image looks unsigned. The table is normally
+    /// the last thing in the file and gets truncated, otherwise it is zeroed. A table that
+    /// claims to be in the headers or sections is left alone. Returns whether there was one.
+    pub fn strip_signature(&self, exe_buf: &mut Vec<u8>) -> bool {
+        let (offset, size) = match self.certificate_table(exe_buf) {
+            Some(table) => table,
+            None => return false,
+        };
+
+        self.set_data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_SECURITY, 0, 0);
+
+        // The section table can start before the end of the data directories
+        let end_of_data_directories = self.data_directories + self.number_of_data_directories * 8;
+        let end_of_image = std::cmp::max(std::cmp::max(self.end_of_headers(), end_of_data_directories), self.end_of_sections());
+        if offset >= end_of_image && offset < exe_buf.len() {
+            let end = std::cmp::min(offset.saturating_add(size), exe_buf.len());
+            // Certificates are padded to 8 bytes
+            if align_up(end as u32, 8) as usize >= exe_buf.len() {
+                exe_buf.truncate(offset);
+            } else {
+                exe_buf[offset .. end].iter_mut().for_each(|byte| *byte = 0);
+            }
+        }
+
+        true
+    }
+
+    pub fn checksum(&self, exe_buf: &[u8]) -> u32 {
+        read_u32(exe_buf, self.optional_header + 64)
+    }
+
+    fn end_of_headers(&self) -> usize {
+        self.section_table + self.sections.len() * SECTION_HEADER_SIZE
+    }
+
+    /// File offset of the end of the last section's raw data, where the overlay starts
+    fn end_of_sections(&self) -> usize {
+        self.sections.iter()
+            .map(|section| section.pointer_to_raw_data as usize + section.size_of_raw_data as usize)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Recomputes the checksum the loader verifies for drivers and some protected binaries.
+    /// Returns the new checksum.
+    pub fn update_checksum(&self, exe_buf: &mut [u8]) -> u32 {
+        let checksum_offset = self.optional_header + 64;
+        write_u32(exe_buf, checksum_offset, 0);
+        let checksum = compute_checksum(exe_buf);
+        write_u32(exe_buf, checksum_offset, checksum);
+
+        checksum
+    }
+
+    /// Returns the RVA and size of a data directory, if the image has one at `index`.
+    pub fn data_directory(&self, exe_buf: &[u8], index: usize) -> Option<(u32, u32)> {
+        if index >= self.number_of_data_directories {
+            return None;
+        }
+
+        let offset = self.data_directories + index * 8;
+        let rva = read_u32(exe_buf, offset);
+        let size = read_u32(exe_buf, offset + 4);
+
+        if rva == 0 && size == 0 {
+            None
+        } else {
+            Some((rva, size))
+        }
+    }
+
+    pub fn set_data_directory(&self, exe_buf: &mut [u8], index: usize, rva: u32, size: u32) {
+        let offset = self.data_directories + index * 8;
+        write_u32(exe_buf, offset, rva);
+        write_u32(exe_buf, offset + 4, size);
+    }
+
+    pub fn rva_to_offset(&self, rva: u32) -> Option<usize> {
+        self.sections.iter()
+            .find(|section| rva >= section.virtual_address && rva - section.virtual_address < section.size_of_raw_data)
+            .map(|section| section.pointer_to_raw_data as usize + (rva - section.virtual_address) as usize)
+    }
+
+    /// The name of the section `rva` is in
+    pub fn section_name_at(&self, rva: u32) -> Option<String> {
+        self.sections.iter()
+            .find(|section| rva >= section.virtual_address && rva - section.virtual_address < section.size_of_raw_data)
+            .map(section_name)
+    }
+
+    pub fn offset_to_rva(&self, offset: usize) -> Option<u32> {
+        self.sections.iter()
+            .find(|section| {
+                let start = section.pointer_to_raw_data as usize;
+                offset >= start && offset - start < section.size_of_raw_data as usize
+            })
+            .and_then(|section| section.virtual_address.checked_add((offset - section.pointer_to_raw_data as usize) as u32))
+    }
+
+    /// Lists every pointer in the base relocation table. Blocks that don't fit in the file are
+    /// ignored.
+    pub fn relocations(&self, exe_buf: &[u8]) -> Vec<Relocation> {
+        let mut relocations = Vec::new();
+
+        let (rva, size) = match self.data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_BASERELOC) {
+            Some(directory) => directory,
+            None => return relocations,
+        };
+        let start = match self.rva_to_offset(rva) {
+            Some(offset) => offset,
+            None => return relocations,
+        };
+        let end = std::cmp::min(start + size as usize, exe_buf.len());
+
+        let mut block = start;
+        while block + 8 <= end {
+            let page_rva = read_u32(exe_buf, block);
+            let block_size = read_u32(exe_buf, block + 4) as usize;
+            if block_size < 8 || block + block_size > end {
+                break;
+            }
+
+            for entry in (block + 8 .. block + block_size).step_by(2) {
+                let entry = read_u16(exe_buf, entry);
+                let size = match entry >> 12 {
+                    IMAGE_REL_BASED_HIGHLOW => 4,
+                    IMAGE_REL_BASED_DIR64 => 8,
+                    _ => continue,
+                };
+
+                if let Some(offset) = self.rva_to_offset(page_rva.wrapping_add((entry & 0xfff) as u32)) {
+                    if offset + size <= exe_buf.len() {
+                        relocations.push(Relocation {
+                            offset,
+                            size,
+                        });
+                    }
+                }
+            }
+
+            block += block_size;
+        }
+
+        relocations
+    }
+
+    /// Reads the RVA a relocated pointer points to.
+    pub fn read_pointer(&self, exe_buf: &[u8], relocation: &Relocation) -> u64 {
+        let value = match relocation.size {
+            4 => read_u32(exe_buf, relocation.offset) as u64,
+            _ => read_u64(exe_buf, relocation.offset),
+        };
+
+        value.wrapping_sub(self.image_base)
+    }
+
+    pub fn write_pointer(&self, exe_buf: &mut [u8], relocation: &Relocation, rva: u32) {
+        let value = self.image_base.wrapping_add(rva as u64);
+
+        match relocation.size {
+            4 => write_u32(exe_buf, relocation.offset, value as u32),
+            _ => write_u64(exe_buf, relocation.offset, value),
+        }
+    }
+
+    /// The RVA the next appended section will be loaded at.
+    pub fn next_section_rva(&self, exe_buf: &[u8]) -> Result<u32> {
+        let end = self.sections.iter()
+            .map(|section| section.virtual_address as u64 + std::cmp::max(section.virtual_size, section.size_of_raw_data) as u64)
+            .max()
+            .unwrap_or(0);
+        let section_alignment = std::cmp::max(self.section_alignment(exe_buf), 1) as u64;
+        let aligned = end.next_multiple_of(section_alignment);
+
+        if aligned > u32::MAX as u64 {
+            return Err(Error::malformed("The sections already fill the address space".to_string()));
+        }
+
+        Ok(aligned as u32)
+    }
+
+    /// Adds a section after the last one, moving any overlay after it. Returns the RVA of the
+    /// new section, which is always `next_section_rva`.
+    pub fn append_section(&mut self, exe_buf: &mut Vec<u8>, name: &str, data: &[u8], characteristics: u32, warnings: &mut Vec<String>) -> Result<u32> {
+        let header_offset = self.end_of_headers();
+        let first_raw_data = self.sections.iter()
+            .map(|section| section.pointer_to_raw_data as usize)
+            .filter(|&pointer| pointer != 0)
+            .min()
+            .unwrap_or(exe_buf.len());
+        let size_of_headers = read_u32(exe_buf, self.optional_header + 60) as usize;
+
+        if header_offset + SECTION_HEADER_SIZE > std::cmp::min(first_raw_data, size_of_headers) {
+            return Err(Error::malformed("No room left in the headers for a new section".to_string()));
+        }
+        if name.len() > 8 {
+            return Err(Error::malformed(format!("Section name {} is longer than 8 bytes", name)));
+        }
+
+        let file_alignment = self.file_alignment(exe_buf);
+        let section_alignment = self.section_alignment(exe_buf);
+
+        let virtual_address = self.next_section_rva(exe_buf)?;
+        let virtual_size = data.len() as u32;
+        let size_of_raw_data = align_up(virtual_size, file_alignment);
+        let end_of_sections = if self.sections.is_empty() { size_of_headers } else { self.end_of_sections() };
+        let pointer_to_raw_data = align_up(end_of_sections as u32, file_alignment);
+
+        // Sections that claim more data than the file holds would have to be padded first
+        if end_of_sections > exe_buf.len() {
+            let last = self.sections.iter()
+                .max_by_key(|section| section.pointer_to_raw_data as usize + section.size_of_raw_data as usize);
+            return Err(match last {
+                Some(section) => Error::out_of_bounds("The last section", section.pointer_to_raw_data as usize, section.size_of_raw_data as usize),
+                None => Error::out_of_bounds("The headers", 0, size_of_headers),
+            });
+        }
+        let fits = (pointer_to_raw_data as u64 + size_of_raw_data as u64) < u32::MAX as u64
+            && (virtual_address as u64 + virtual_size as u64) < u32::MAX as u64
+            && pointer_to_raw_data as usize >= end_of_sections;
+        if !fits {
+            return Err(Error::malformed(format!("Section {} doesn't fit after the last section", name)));
+        }
+
+        // Anything past the last section is overlay data the loader doesn't map, such as the
+        // certificate table. It's kept after the new section.
+        let overlay = if exe_buf.len() > end_of_sections {
+            exe_buf.split_off(end_of_sections)
+        } else {
+            Vec::new()
+        };
+        if !overlay.is_empty() {
+            warnings.push(format!("Moving {} bytes of overlay data after the new section {}", overlay.len(), name));
+        }
+
+        exe_buf.resize(pointer_to_raw_data as usize, 0);
+        exe_buf.extend_from_slice(data);
+        exe_buf.resize((pointer_to_raw_data + size_of_raw_data) as usize, 0);
+        exe_buf.extend_from_slice(&overlay);
+
+        let mut section = SectionTable::default();
+        section.name[.. name.len()].copy_from_slice(name.as_bytes());
+        section.virtual_size = virtual_size;
+        section.virtual_address = virtual_address;
+        section.size_of_raw_data = size_of_raw_data;
+        section.pointer_to_raw_data = pointer_to_raw_data;
+        section.characteristics = characteristics;
+
+        let header = &mut exe_buf[header_offset .. header_offset + SECTION_HEADER_SIZE];
+        header.iter_mut().for_each(|byte| *byte = 0);
+        header[0 .. 8].copy_from_slice(&section.name);
+        write_u32(header, 8, section.virtual_size);
+        write_u32(header, 12, section.virtual_address);
+        write_u32(header, 16, section.size_of_raw_data);
+        write_u32(header, 20, section.pointer_to_raw_data);
+        write_u32(header, 36, section.characteristics);
+
+        self.sections.push(section);
+
+        let coff_header = self.optional_header - 20;
+        write_u16(exe_buf, coff_header + 2, self.sections.len() as u16);
+
+        let size_of_initialized_data = read_u32(exe_buf, self.optional_header + 8);
+        write_u32(exe_buf, self.optional_header + 8, size_of_initialized_data.wrapping_add(size_of_raw_data));
+        write_u32(exe_buf, self.optional_header + 56, align_up(virtual_address + virtual_size, section_alignment));
+
+        // The certificate table is the only data directory holding a file offset
+        if let Some((offset, size)) = self.data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_SECURITY) {
+            if offset as usize >= end_of_sections {
+                let moved = offset as usize + (pointer_to_raw_data + size_of_raw_data) as usize - end_of_sections;
+                self.set_data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_SECURITY, moved as u32, size);
+            }
+        }
+
+        Ok(virtual_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A PE32 image with no sections, and bytes that aren't all zeroes after the headers
+    fn minimal_pe(size: usize) -> Vec<u8> {
+        let mut exe_buf = vec![0; size];
+        exe_buf[.. 2].copy_from_slice(b"MZ");
+        write_u32(&mut exe_buf, 0x3c, 0x40);
+        exe_buf[0x40 .. 0x44].copy_from_slice(b"PE\0\0");
+        write_u16(&mut exe_buf, 0x44, 0x14c);
+        write_u16(&mut exe_buf, 0x44 + 16, 0xe0);
+        write_u16(&mut exe_buf, 0x44 + 18, 0x102);
+
+        let optional_header = 0x58;
+        write_u16(&mut exe_buf, optional_header, 0x10b);
+        write_u32(&mut exe_buf, optional_header + 28, 0x40_0000);
+        write_u32(&mut exe_buf, optional_header + 32, 0x1000);
+        write_u32(&mut exe_buf, optional_header + 36, 0x200);
+        write_u32(&mut exe_buf, optional_header + 56, 0x1000);
+        write_u32(&mut exe_buf, optional_header + 60, 0x200);
+        write_u16(&mut exe_buf, optional_header + 68, 2);
+        write_u32(&mut exe_buf, optional_header + 92, 16);
+
+        for (i, byte) in exe_buf.iter_mut().enumerate().skip(0x180) {
+            *byte = (i * 7 + 1) as u8;
+        }
+
+        exe_buf
+    }
+
+    #[test]
+    fn checksum_folds_carries() {
+        assert_eq!(compute_checksum(&[0x01, 0x00, 0x02, 0x00]), 3 + 4);
+        // 0xffff + 0xffff folds to 0xffff, and the last byte of an odd-length file is a word
+        assert_eq!(compute_checksum(&[0xff, 0xff, 0xff, 0xff, 0x01]), 1 + 5);
+    }
+
+    // The expected checksums were computed with the 32-bit algorithm of pefile, which gives the
+    // checksums written by Microsoft's linker
+    #[test]
+    fn updates_checksum() {
+        let mut exe_buf = minimal_pe(0x200);
+        let image = Image::parse(&exe_buf).unwrap();
+        write_u32(&mut exe_buf, image.optional_header + 64, 0x1234_5678);
+
+        assert_eq!(image.update_checksum(&mut exe_buf), 0x2d0b);
+        assert_eq!(image.checksum(&exe_buf), 0x2d0b);
+    }
+
+    #[test]
+    fn updates_checksum_of_odd_length_file() {
+        let mut exe_buf = minimal_pe(0x201);
+        let image = Image::parse(&exe_buf).unwrap();
+
+        assert_eq!(image.update_checksum(&mut exe_buf), 0x2d0d);
+    }
+}
@@ -0,0 +1,301 @@
+//! gettext PO files, as edited in Poedit or Weblate. `msgid` is the original text and `msgstr`
+//! the translated text. `msgctxt` limits the entry to the section of that name, and an
+//! `encoding=NAME` flag gives its encoding, like the third column of a CSV file.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::encoding::Encoding;
+use crate::extract::{self, ExtractedString};
+use crate::{Translation, TranslationSet};
+
+/// The fields of an entry that are read, each with the line it starts on
+#[derive(Default)]
+struct Entry {
+    context: Option<String>,
+    id: Option<(usize, String)>,
+    plural: bool,
+    translated: Option<String>,
+    fuzzy: bool,
+    encoding: Option<String>,
+}
+
+/// Which string the continuation lines are appended to
+enum Field {
+    None,
+    Context,
+    Id,
+    Plural,
+    Translated,
+}
+
+fn unescape(string: &str) -> String {
+    let mut unescaped = String::with_capacity(string.len());
+    let mut chars = string.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+fn escape(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+
+    for c in string.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// The string between the quotes of `rest`, if it is quoted
+fn quoted(rest: &str) -> Option<String> {
+    let rest = rest.trim();
+    if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+        Some(unescape(&rest[1 .. rest.len() - 1]))
+    } else {
+        None
+    }
+}
+
+impl Entry {
+    fn is_empty(&self) -> bool {
+        self.context.is_none() && self.id.is_none() && self.translated.is_none()
+    }
+
+    /// Turns the entry into a translation, unless it is the header, isn't translated yet, or
+    /// can't be used
+    fn into_translation(self, rejected: &mut Vec<String>) -> Option<Translation> {
+        let (line, original) = self.id?;
+
+        // The header has an empty msgid
+        if original.is_empty() {
+            return None;
+        }
+
+        if self.plural {
+            rejected.push(format!("Plural entries aren't supported line {}: {:?}", line, original));
+            return None;
+        }
+
+        if self.fuzzy {
+            rejected.push(format!("Skipping fuzzy entry line {}: {:?}", line, original));
+            return None;
+        }
+
+        let translated = match self.translated {
+            Some(translated) if !translated.is_empty() => translated,
+            _ => return None,
+        };
+
+        let encoding = match self.encoding {
+            Some(name) => if let Some(encoding) = Encoding::from_name(&name) {
+                Some(encoding)
+            } else {
+                rejected.push(format!("Unknown encoding {} line {}", name, line));
+                return None;
+            },
+            None => None,
+        };
+
+        Some(Translation {
+            original,
+            translated,
+            encoding,
+            section: self.context.filter(|section| !section.is_empty()),
+        })
+    }
+}
+
+/// Reads the translated entries of a PO file. Untranslated entries and the header are left out
+/// silently, fuzzy entries and those that can't be read are recorded in `rejected`. Obsolete
+/// entries are ignored.
+pub fn read(contents: &str) -> TranslationSet {
+    let mut translations = Vec::new();
+    let mut rejected = Vec::new();
+    let mut entry = Entry::default();
+    let mut field = Field::None;
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+
+        // A comment or a keyword after a msgstr starts the next entry
+        let starts_entry = line.starts_with('#') || line.starts_with("msgctxt") || line.starts_with("msgid ");
+        if line.is_empty() || (starts_entry && entry.translated.is_some()) {
+            if let Some(translation) = std::mem::take(&mut entry).into_translation(&mut rejected) {
+                translations.push(translation);
+            }
+            field = Field::None;
+        }
+
+        if line.is_empty() || line.starts_with("#~") {
+            continue;
+        }
+
+        if let Some(flags) = line.strip_prefix("#,") {
+            for flag in flags.split(',').map(str::trim) {
+                if flag == "fuzzy" {
+                    entry.fuzzy = true;
+                } else if let Some(name) = flag.strip_prefix("encoding=") {
+                    entry.encoding = Some(name.to_string());
+                }
+            }
+            continue;
+        }
+
+        if line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('"') {
+            let string = if let Some(string) = quoted(line) {
+                string
+            } else {
+                rejected.push(format!("Unterminated string line {}", line_number));
+                continue;
+            };
+
+            match field {
+                Field::Context => entry.context.get_or_insert_with(String::new).push_str(&string),
+                Field::Id => entry.id.get_or_insert((line_number, String::new())).1.push_str(&string),
+                Field::Translated => entry.translated.get_or_insert_with(String::new).push_str(&string),
+                Field::Plural => (),
+                Field::None => rejected.push(format!("String outside of an entry line {}", line_number)),
+            }
+            continue;
+        }
+
+        let (keyword, rest) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[.. index], &line[index ..]),
+            None => (line, ""),
+        };
+        let string = if let Some(string) = quoted(rest) {
+            string
+        } else {
+            rejected.push(format!("Expected a string after {} line {}", keyword, line_number));
+            continue;
+        };
+
+        match keyword {
+            "msgctxt" => {
+                entry.context = Some(string);
+                field = Field::Context;
+            },
+            "msgid" => {
+                entry.id = Some((line_number, string));
+                field = Field::Id;
+            },
+            "msgid_plural" => {
+                entry.plural = true;
+                field = Field::Plural;
+            },
+            // Plural entries are rejected anyway, their first form is as good as any
+            "msgstr" | "msgstr[0]" => {
+                entry.translated = Some(string);
+                field = Field::Translated;
+            },
+            _ if keyword.starts_with("msgstr[") => field = Field::Plural,
+            _ => rejected.push(format!("Unknown keyword {} line {}", keyword, line_number)),
+        }
+    }
+
+    if !entry.is_empty() {
+        if let Some(translation) = entry.into_translation(&mut rejected) {
+            translations.push(translation);
+        }
+    }
+
+    TranslationSet {
+        translations,
+        rejected,
+    }
+}
+
+/// Writes a quoted string, splitting it after each line break the way gettext does
+fn write_string<W: Write>(writer: &mut W, keyword: &str, string: &str) -> io::Result<()> {
+    let lines: Vec<&str> = string.split_inclusive('\n').collect();
+
+    if lines.len() > 1 {
+        writeln!(writer, "{} \"\"", keyword)?;
+        for line in lines {
+            writeln!(writer, "\"{}\"", escape(line))?;
+        }
+        Ok(())
+    } else {
+        writeln!(writer, "{} \"{}\"", keyword, escape(string))
+    }
+}
+
+/// The distinct texts of each section, with their occurrences in it
+fn group_by_section(strings: &[ExtractedString]) -> Vec<(&str, Vec<&ExtractedString>)> {
+    let mut groups = Vec::new();
+
+    for (text, occurrences) in extract::group_by_text(strings) {
+        let mut sections: Vec<Vec<&ExtractedString>> = Vec::new();
+        for string in occurrences {
+            match sections.iter_mut().find(|section| section[0].section == string.section) {
+                Some(section) => section.push(string),
+                None => sections.push(vec![string]),
+            }
+        }
+        groups.extend(sections.into_iter().map(|section| (text, section)));
+    }
+
+    groups
+}
+
+/// Writes the strings as a POT template, with an entry per text of each section, grouped like
+/// `extract::group_by_text` does. Entries have the section as their context, the encoding as a
+/// flag, and a reference to the address of each occurrence. Returns the number of entries
+/// written.
+pub fn write_template(pot_path: &str, strings: &[ExtractedString]) -> io::Result<usize> {
+    let fd = File::create(pot_path)?;
+    let mut writer = BufWriter::new(fd);
+
+    let texts = group_by_section(strings);
+
+    writeln!(writer, "msgid \"\"")?;
+    writeln!(writer, "msgstr \"\"")?;
+    writeln!(writer, "\"Content-Type: text/plain; charset=UTF-8\\n\"")?;
+    writeln!(writer, "\"Content-Transfer-Encoding: 8bit\\n\"")?;
+
+    for (text, occurrences) in texts.iter() {
+        writeln!(writer)?;
+        for string in occurrences.iter() {
+            // References are separated by spaces
+            match &string.architecture {
+                Some(architecture) => writeln!(writer, "#: {}[{}]:{:#x}", string.section, architecture, string.address)?,
+                None => writeln!(writer, "#: {}:{:#x}", string.section, string.address)?,
+            }
+        }
+        writeln!(writer, "#, encoding={}", occurrences[0].encoding)?;
+        write_string(&mut writer, "msgctxt", &occurrences[0].section)?;
+        write_string(&mut writer, "msgid", text)?;
+        writeln!(writer, "msgstr \"\"")?;
+    }
+
+    writer.flush()?;
+
+    Ok(texts.len())
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use goblin::pe::section_table::{IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ};
+
+use crate::error::{Error, Result};
+use crate::pe::{self, Image};
+
+pub const SECTION_NAME: &str = ".trans";
+
+/// A translation that takes more bytes than the original string it replaces.
+pub struct Overflow {
+    /// File offset of the original string
+    pub offset: usize,
+    pub original: String,
+    pub translated: Vec<u8>,
+}
+
+/// Copies the translations into a new section, and points every absolute reference to the
+/// original strings to their copy instead. The original strings are left untouched so that
+/// references we couldn't find still point to valid text. Returns the number of references
+/// that were rewritten.
+pub fn relocate(exe_buf: &mut Vec<u8>, overflows: &[Overflow], warnings: &mut Vec<String>) -> Result<usize> {
+    let mut image = Image::parse(exe_buf)?;
+    let section_rva = image.next_section_rva(exe_buf)?;
+
+    // Identical translations share the same copy
+    let mut data = Vec::new();
+    let mut copies = HashMap::new();
+    let mut new_rvas = HashMap::new();
+    for overflow in overflows.iter() {
+        let original_rva = image.offset_to_rva(overflow.offset)
+            .ok_or_else(|| Error::malformed(format!("{} isn't mapped in memory", overflow.original)))?;
+
+        let copy_offset = *copies.entry(&overflow.translated).or_insert_with(|| {
+            let copy_offset = pe::align_up(data.len() as u32, 4);
+            data.resize(copy_offset as usize, 0);
+            data.extend_from_slice(&overflow.translated);
+            copy_offset
+        });
+
+        new_rvas.insert(original_rva as u64, section_rva + copy_offset);
+    }
+
+    if data.is_empty() {
+        return Ok(0);
+    }
+
+    let relocations = image.relocations(exe_buf);
+    if relocations.is_empty() {
+        warnings.push(format!("{} has no relocation table, no references to the relocated strings can be found", SECTION_NAME));
+    }
+
+    let mut rewritten_per_rva: HashMap<u64, usize> = HashMap::new();
+    for relocation in relocations.iter() {
+        let target = image.read_pointer(exe_buf, relocation);
+
+        if let Some(&new_rva) = new_rvas.get(&target) {
+            image.write_pointer(exe_buf, relocation, new_rva);
+            *rewritten_per_rva.entry(target).or_insert(0) += 1;
+        }
+    }
+
+    for overflow in overflows.iter() {
+        let original_rva = image.offset_to_rva(overflow.offset).unwrap() as u64;
+
+        if !rewritten_per_rva.contains_key(&original_rva) {
+            warnings.push(format!("No absolute reference to {} at RVA {:#x}. It may be referenced relatively and will stay untranslated.", overflow.original, original_rva));
+        }
+    }
+
+    image.append_section(exe_buf, SECTION_NAME, &data, IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ, warnings)?;
+
+    Ok(rewritten_per_rva.values().sum())
+}
@@ -0,0 +1,127 @@
+//! Machine-readable report of what `translate` did, meant to be diffed between builds.
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use serde::Serialize;
+
+use crate::matcher::Action;
+use crate::patcher::{OccurrenceResult, PatchResult};
+use crate::error::{Error, Result};
+use crate::TranslationSet;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    /// At least one occurrence was replaced or relocated, or a resource or user string was
+    /// replaced
+    Applied,
+    /// The original text was found, but no occurrence could be replaced
+    NotApplied,
+    /// The original text wasn't found in the selected sections, resources or user strings
+    Unmatched,
+    /// The translation couldn't be used at all
+    Skipped,
+}
+
+#[derive(Serialize)]
+struct EntryReport<'a> {
+    original: &'a str,
+    translated: &'a str,
+    encoding: String,
+    original_bytes: Option<usize>,
+    translated_bytes: Option<usize>,
+    status: Status,
+    reason: Option<&'a str>,
+    occurrences: &'a [OccurrenceResult],
+    resource_replacements: usize,
+    user_string_replacements: usize,
+}
+
+#[derive(Serialize)]
+struct ConflictReport {
+    section: String,
+    offset: usize,
+    original: String,
+    kept_offset: usize,
+    kept_original: String,
+}
+
+#[derive(Serialize)]
+pub struct Report<'a> {
+    exe_file: &'a str,
+    translation_file: &'a str,
+    rejected_rows: &'a [String],
+    /// One per translation that was read, in the same order
+    entries: Vec<EntryReport<'a>>,
+    conflicts: Vec<ConflictReport>,
+    relocated_strings: usize,
+    rewritten_references: usize,
+    resource_replacements: Option<usize>,
+    user_string_replacements: Option<usize>,
+    warnings: &'a [String],
+}
+
+impl<'a> Report<'a> {
+    pub fn new(exe_file: &'a str, translation_file: &'a str, translations: &'a TranslationSet, result: &'a PatchResult) -> Report<'a> {
+        let entries = translations.translations.iter()
+            .zip(result.entries.iter())
+            .map(|(translation, entry)| {
+                let applied = entry.resource_replacements > 0 || entry.user_string_replacements > 0 || entry.occurrences.iter()
+                    .any(|occurrence| matches!(occurrence.action, Action::Replaced | Action::Overwritten | Action::Relocated));
+
+                let status = if entry.skipped.is_some() {
+                    Status::Skipped
+                } else if applied {
+                    Status::Applied
+                } else if !entry.occurrences.is_empty() || entry.conflicts > 0 {
+                    Status::NotApplied
+                } else {
+                    Status::Unmatched
+                };
+
+                EntryReport {
+                    original: &translation.original,
+                    translated: &translation.translated,
+                    encoding: entry.encoding.to_string(),
+                    original_bytes: entry.original_bytes,
+                    translated_bytes: entry.translated_bytes,
+                    status,
+                    reason: entry.skipped.as_deref(),
+                    occurrences: &entry.occurrences,
+                    resource_replacements: entry.resource_replacements,
+                    user_string_replacements: entry.user_string_replacements,
+                }
+            })
+            .collect();
+
+        let conflicts = result.conflicts.iter()
+            .map(|conflict| ConflictReport {
+                section: conflict.section.clone(),
+                offset: conflict.offset,
+                original: translations.translations[conflict.translation].original.clone(),
+                kept_offset: conflict.kept_offset,
+                kept_original: translations.translations[conflict.kept_translation].original.clone(),
+            })
+            .collect();
+
+        Report {
+            exe_file,
+            translation_file,
+            rejected_rows: &translations.rejected,
+            entries,
+            conflicts,
+            relocated_strings: result.relocated_strings,
+            rewritten_references: result.rewritten_references,
+            resource_replacements: result.resources.as_ref().map(|counts| counts.total()),
+            user_string_replacements: result.user_strings.as_ref().map(|counts| counts.replaced),
+            warnings: &result.warnings,
+        }
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let writer = BufWriter::new(File::create(path).map_err(|error| Error::io(path, error))?);
+
+        serde_json::to_writer_pretty(writer, self).map_err(|error| Error::io(path, error.into()))
+    }
+}
@@ -0,0 +1,150 @@
+//! RT_DIALOG resources, in both the DLGTEMPLATE and DLGTEMPLATEEX formats.
+
+use super::{Data, Lookup, Reader, SzOrOrd};
+use crate::pe;
+
+const DS_SETFONT: u32 = 0x40;
+/// Marks a DLGTEMPLATEEX, in place of the low word of DLGTEMPLATE's style
+const EXTENDED_SIGNATURE: u16 = 0xffff;
+
+struct Font {
+    /// Point size, and for DLGTEMPLATEEX weight, italic and charset
+    fields: Vec<u8>,
+    typeface: Vec<u16>,
+}
+
+struct Control {
+    /// Everything up to the class, kept as is
+    fields: Vec<u8>,
+    class: SzOrOrd,
+    title: SzOrOrd,
+    creation_data: Vec<u8>,
+}
+
+struct Dialog {
+    /// Everything up to the menu, kept as is
+    fields: Vec<u8>,
+    menu: SzOrOrd,
+    class: SzOrOrd,
+    title: Vec<u16>,
+    font: Option<Font>,
+    controls: Vec<Control>,
+}
+
+impl Dialog {
+    fn parse(bytes: &[u8]) -> Option<Dialog> {
+        let mut reader = Reader::new(bytes);
+
+        let extended = bytes.len() >= 4 && pe::read_u16(bytes, 2) == EXTENDED_SIGNATURE;
+        let (fields, style, count) = if extended {
+            let fields = reader.read_bytes(26)?;
+            (fields, pe::read_u32(fields, 12), pe::read_u16(fields, 16))
+        } else {
+            let fields = reader.read_bytes(18)?;
+            (fields, pe::read_u32(fields, 0), pe::read_u16(fields, 8))
+        };
+
+        let menu = reader.read_sz_or_ord()?;
+        let class = reader.read_sz_or_ord()?;
+        let title = reader.read_sz()?;
+
+        let font = if style & DS_SETFONT != 0 {
+            Some(Font {
+                fields: reader.read_bytes(if extended { 6 } else { 2 })?.to_vec(),
+                typeface: reader.read_sz()?,
+            })
+        } else {
+            None
+        };
+
+        let mut controls = Vec::with_capacity(count as usize);
+        for _ in 0 .. count {
+            reader.align(4);
+
+            let fields = reader.read_bytes(if extended { 24 } else { 18 })?.to_vec();
+            let class = reader.read_sz_or_ord()?;
+            let title = reader.read_sz_or_ord()?;
+            let creation_data_size = reader.read_u16()?;
+            let creation_data = reader.read_bytes(creation_data_size as usize)?.to_vec();
+
+            controls.push(Control {
+                fields,
+                class,
+                title,
+                creation_data,
+            });
+        }
+
+        Some(Dialog {
+            fields: fields.to_vec(),
+            menu,
+            class,
+            title,
+            font,
+            controls,
+        })
+    }
+
+    fn build(&self) -> Vec<u8> {
+        let mut buf = self.fields.clone();
+
+        super::write_sz_or_ord(&mut buf, &self.menu);
+        super::write_sz_or_ord(&mut buf, &self.class);
+        super::write_sz(&mut buf, &self.title);
+
+        if let Some(font) = &self.font {
+            buf.extend_from_slice(&font.fields);
+            super::write_sz(&mut buf, &font.typeface);
+        }
+
+        for control in self.controls.iter() {
+            super::pad(&mut buf, 4);
+
+            buf.extend_from_slice(&control.fields);
+            super::write_sz_or_ord(&mut buf, &control.class);
+            super::write_sz_or_ord(&mut buf, &control.title);
+            super::write_u16(&mut buf, control.creation_data.len() as u16);
+            buf.extend_from_slice(&control.creation_data);
+        }
+
+        buf
+    }
+}
+
+/// Translates the caption, font name and control texts of a dialog. Returns the number of
+/// strings replaced.
+pub fn translate(data: &mut Data, lookup: &Lookup, warnings: &mut Vec<String>) -> usize {
+    let mut dialog = match Dialog::parse(&data.bytes) {
+        Some(dialog) => dialog,
+        None => {
+            warnings.push("Truncated dialog template, skipping it".to_string());
+            return 0;
+        },
+    };
+
+    let mut replaced = 0;
+
+    if super::translate_units(&mut dialog.title, lookup) {
+        replaced += 1;
+    }
+
+    if let Some(font) = &mut dialog.font {
+        if super::translate_units(&mut font.typeface, lookup) {
+            replaced += 1;
+        }
+    }
+
+    for control in dialog.controls.iter_mut() {
+        if let SzOrOrd::Sz(title) = &mut control.title {
+            if super::translate_units(title, lookup) {
+                replaced += 1;
+            }
+        }
+    }
+
+    if replaced > 0 {
+        data.bytes = dialog.build();
+    }
+
+    replaced
+}
@@ -0,0 +1,238 @@
+//! RT_MENU resources, in both the MENUITEMTEMPLATE and MENUEX formats.
+
+use super::{Data, Lookup, Reader};
+use crate::pe;
+
+const MF_POPUP: u16 = 0x10;
+const MF_END: u16 = 0x80;
+/// In MENUEX items, marks a popup in bResInfo
+const MFR_POPUP: u16 = 0x01;
+const MFR_END: u16 = 0x80;
+const MENUEX_VERSION: u16 = 1;
+/// Real menus nest a few popups at most, deeper ones would only exhaust the stack
+const MAX_DEPTH: usize = 32;
+
+struct Item {
+    popup: bool,
+    /// Everything before the text, kept as is
+    fields: Vec<u8>,
+    text: Vec<u16>,
+    /// For MENUEX popups, the help id following the text
+    help_id: Vec<u8>,
+    children: Vec<Item>,
+}
+
+struct Menu {
+    extended: bool,
+    header: Vec<u8>,
+    items: Vec<Item>,
+}
+
+impl Menu {
+    fn parse(bytes: &[u8]) -> Option<Menu> {
+        let mut reader = Reader::new(bytes);
+
+        let version = reader.read_u16()?;
+        let header_size = reader.read_u16()?;
+        let extended = version == MENUEX_VERSION;
+
+        // The header size counts from the end of the two fields above
+        reader.read_bytes(header_size as usize)?;
+        let header = bytes[.. reader.offset].to_vec();
+
+        let items = if extended {
+            parse_extended_level(&mut reader, 0)?
+        } else {
+            parse_level(&mut reader, 0)?
+        };
+
+        Some(Menu {
+            extended,
+            header,
+            items,
+        })
+    }
+
+    fn build(&self) -> Vec<u8> {
+        let mut buf = self.header.clone();
+
+        build_level(&mut buf, &self.items, self.extended);
+
+        buf
+    }
+}
+
+fn parse_level(reader: &mut Reader, depth: usize) -> Option<Vec<Item>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    let mut items = Vec::new();
+
+    loop {
+        let option = reader.read_u16()?;
+        let popup = option & MF_POPUP != 0;
+
+        let mut fields = option.to_le_bytes().to_vec();
+        if !popup {
+            // Menu id
+            fields.extend_from_slice(reader.read_bytes(2)?);
+        }
+
+        let text = reader.read_sz()?;
+        let children = if popup {
+            parse_level(reader, depth + 1)?
+        } else {
+            Vec::new()
+        };
+
+        items.push(Item {
+            popup,
+            fields,
+            text,
+            help_id: Vec::new(),
+            children,
+        });
+
+        if option & MF_END != 0 {
+            return Some(items);
+        }
+    }
+}
+
+fn parse_extended_level(reader: &mut Reader, depth: usize) -> Option<Vec<Item>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    let mut items = Vec::new();
+
+    loop {
+        reader.align(4);
+
+        // Type, state, id and bResInfo
+        let fields = reader.read_bytes(14)?.to_vec();
+        let res_info = pe::read_u16(&fields, 12);
+        let popup = res_info & MFR_POPUP != 0;
+
+        let text = reader.read_sz()?;
+        reader.align(4);
+
+        let (help_id, children) = if popup {
+            (reader.read_bytes(4)?.to_vec(), parse_extended_level(reader, depth + 1)?)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        items.push(Item {
+            popup,
+            fields,
+            text,
+            help_id,
+            children,
+        });
+
+        if res_info & MFR_END != 0 {
+            return Some(items);
+        }
+    }
+}
+
+fn build_level(buf: &mut Vec<u8>, items: &[Item], extended: bool) {
+    for item in items.iter() {
+        if extended {
+            super::pad(buf, 4);
+        }
+
+        buf.extend_from_slice(&item.fields);
+        super::write_sz(buf, &item.text);
+
+        if item.popup {
+            if extended {
+                super::pad(buf, 4);
+                buf.extend_from_slice(&item.help_id);
+            }
+
+            build_level(buf, &item.children, extended);
+        }
+    }
+}
+
+/// Returns the lowercased letter following the first lone `&`, which Windows underlines and
+/// uses as the keyboard shortcut. `&&` is a literal ampersand.
+fn mnemonic(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '&' {
+            continue;
+        }
+
+        match chars.next() {
+            Some('&') => continue,
+            Some(letter) => return letter.to_lowercase().next(),
+            None => return None,
+        }
+    }
+
+    None
+}
+
+fn translate_level(items: &mut [Item], lookup: &Lookup, warnings: &mut Vec<String>) -> usize {
+    let mut replaced = 0;
+    let mut level_replaced = 0;
+
+    for item in items.iter_mut() {
+        let original = String::from_utf16_lossy(&item.text);
+
+        if super::translate_units(&mut item.text, lookup) {
+            level_replaced += 1;
+
+            let translated = String::from_utf16_lossy(&item.text);
+            if mnemonic(&original).is_some() && mnemonic(&translated).is_none() {
+                warnings.push(format!("Menu item {} lost its & mnemonic in translation {}", original, translated));
+            }
+        }
+
+        replaced += translate_level(&mut item.children, lookup, warnings);
+    }
+
+    if level_replaced == 0 {
+        return replaced;
+    }
+
+    let texts: Vec<String> = items.iter()
+        .map(|item| String::from_utf16_lossy(&item.text))
+        .collect();
+    for (i, text) in texts.iter().enumerate() {
+        let letter = match mnemonic(text) {
+            Some(letter) => letter,
+            None => continue,
+        };
+
+        if let Some(other) = texts[.. i].iter().find(|other| mnemonic(other) == Some(letter)) {
+            warnings.push(format!("Menu items {} and {} use the same mnemonic &{}", other, text, letter));
+        }
+    }
+
+    replaced + level_replaced
+}
+
+/// Translates the item texts of a menu. Returns the number of strings replaced.
+pub fn translate(data: &mut Data, lookup: &Lookup, warnings: &mut Vec<String>) -> usize {
+    let mut menu = match Menu::parse(&data.bytes) {
+        Some(menu) => menu,
+        None => {
+            warnings.push("Truncated menu template, skipping it".to_string());
+            return 0;
+        },
+    };
+
+    let replaced = translate_level(&mut menu.items, lookup, warnings);
+
+    if replaced > 0 {
+        data.bytes = menu.build();
+    }
+
+    replaced
+}
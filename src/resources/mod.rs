@@ -0,0 +1,516 @@
+//! Parsing and rebuilding of the resource directory tree in `.rsrc`.
+
+mod dialog;
+mod menu;
+mod string_table;
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+
+use goblin::pe::section_table::{IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_READ};
+
+use crate::error::{Error, Result};
+use crate::pe::{self, Image};
+use crate::Translation;
+
+pub const IMAGE_DIRECTORY_ENTRY_RESOURCE: usize = 2;
+pub const RT_MENU: u16 = 4;
+pub const RT_DIALOG: u16 = 5;
+pub const RT_STRING: u16 = 6;
+
+/// Name of the section resources are moved to when they outgrow the original one
+pub const SECTION_NAME: &str = ".rsrc2";
+
+/// The tree is normally type / name / language, anything deeper is either corrupt or a loop.
+const MAX_DEPTH: usize = 8;
+
+/// Strings replaced in each kind of resource
+#[derive(Debug, Default)]
+pub struct ResourceCounts {
+    pub string_tables: usize,
+    pub dialogs: usize,
+    pub menus: usize,
+    /// Set when the resources outgrew their section and were moved to a new one
+    pub moved_to: Option<&'static str>,
+    /// Strings replaced for each translation of the set, in the same order. Empty if nothing
+    /// was replaced.
+    pub translations: Vec<usize>,
+}
+
+impl ResourceCounts {
+    pub fn total(&self) -> usize {
+        self.string_tables + self.dialogs + self.menus
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ResourceId {
+    Id(u16),
+    /// Kept as UTF-16 so that it is written back exactly as it was read
+    Name(Vec<u16>),
+}
+
+pub struct Directory {
+    pub characteristics: u32,
+    pub time_date_stamp: u32,
+    pub major_version: u16,
+    pub minor_version: u16,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub id: ResourceId,
+    pub node: Node,
+}
+
+pub enum Node {
+    Directory(Directory),
+    Data(Data),
+}
+
+pub struct Data {
+    pub bytes: Vec<u8>,
+    pub code_page: u32,
+    pub reserved: u32,
+}
+
+/// Maps each original string to its translation for the resource formats, which always store
+/// UTF-16 strings and don't care about lengths. Counts the strings replaced by each translation.
+pub struct Lookup<'a> {
+    translations: HashMap<&'a str, (usize, &'a str)>,
+    replaced: Vec<Cell<usize>>,
+}
+
+impl<'a> Lookup<'a> {
+    /// Translations scoped to another section than `section`, the one holding the strings,
+    /// are left out
+    pub fn new(translations: &'a [Translation], section: &str) -> Lookup<'a> {
+        let mut map = HashMap::new();
+        for (index, translation) in translations.iter().enumerate() {
+            if translation.section.as_ref().is_some_and(|name| name != section) {
+                continue;
+            }
+
+            // Like in sections, only the first translation of a text is used
+            map.entry(translation.original.as_str()).or_insert((index, translation.translated.as_str()));
+        }
+
+        Lookup {
+            translations: map,
+            replaced: vec![Cell::new(0); translations.len()],
+        }
+    }
+
+    /// The translation of `original`, which is counted as replaced
+    pub fn get(&self, original: &str) -> Option<&'a str> {
+        let &(index, translated) = self.translations.get(original)?;
+        self.replaced[index].set(self.replaced[index].get() + 1);
+
+        Some(translated)
+    }
+
+    /// Takes back a replacement counted by `get`
+    pub fn restore(&self, original: &str) {
+        if let Some(&(index, _)) = self.translations.get(original) {
+            self.replaced[index].set(self.replaced[index].get() - 1);
+        }
+    }
+
+    /// Strings replaced for each translation, in the order of the set
+    pub fn replaced(&self) -> Vec<usize> {
+        self.replaced.iter().map(Cell::get).collect()
+    }
+}
+
+/// What has been parsed so far, so that entries sharing a subdirectory or data can't make the
+/// tree grow exponentially
+#[derive(Default)]
+struct ParseState {
+    directories: HashSet<usize>,
+    data_size: usize,
+}
+
+impl Directory {
+    /// Parses the tree starting at `base`, the file offset of the root directory.
+    pub fn parse(exe_buf: &[u8], image: &Image, base: usize) -> Result<Directory> {
+        Directory::parse_at(exe_buf, image, base, 0, 0, &mut ParseState::default())
+    }
+
+    fn parse_at(exe_buf: &[u8], image: &Image, base: usize, offset: usize, depth: usize, state: &mut ParseState) -> Result<Directory> {
+        if depth > MAX_DEPTH {
+            return Err(Error::malformed("Resource directory is too deep".to_string()));
+        }
+
+        let start = base + offset;
+        check_bounds(exe_buf, start, 16)?;
+        // Resource compilers never share a directory between entries
+        if !state.directories.insert(start) {
+            return Err(Error::malformed(format!("Resource directory at offset {:#x} is used more than once", start)));
+        }
+
+        let number_of_entries = pe::read_u16(exe_buf, start + 12) as usize + pe::read_u16(exe_buf, start + 14) as usize;
+        check_bounds(exe_buf, start + 16, number_of_entries * 8)?;
+
+        let mut entries = Vec::with_capacity(number_of_entries);
+        for i in 0 .. number_of_entries {
+            let entry = start + 16 + i * 8;
+            let name = pe::read_u32(exe_buf, entry);
+            let offset_to_data = pe::read_u32(exe_buf, entry + 4);
+
+            let id = if name & 0x8000_0000 != 0 {
+                let name_offset = base + (name & 0x7fff_ffff) as usize;
+                check_bounds(exe_buf, name_offset, 2)?;
+                let length = pe::read_u16(exe_buf, name_offset) as usize;
+                check_bounds(exe_buf, name_offset + 2, length * 2)?;
+
+                ResourceId::Name((0 .. length).map(|j| pe::read_u16(exe_buf, name_offset + 2 + j * 2)).collect())
+            } else {
+                ResourceId::Id(name as u16)
+            };
+
+            let node = if offset_to_data & 0x8000_0000 != 0 {
+                Node::Directory(Directory::parse_at(exe_buf, image, base, (offset_to_data & 0x7fff_ffff) as usize, depth + 1, state)?)
+            } else {
+                let data_entry = base + offset_to_data as usize;
+                check_bounds(exe_buf, data_entry, 16)?;
+
+                let rva = pe::read_u32(exe_buf, data_entry);
+                let size = pe::read_u32(exe_buf, data_entry + 4) as usize;
+                let data_offset = image.rva_to_offset(rva)
+                    .ok_or_else(|| Error::malformed(format!("Resource data at RVA {:#x} isn't in any section", rva)))?;
+                check_bounds(exe_buf, data_offset, size)?;
+
+                // Data can be shared, but all of it being copied can't take more than the file
+                state.data_size += size;
+                if state.data_size > exe_buf.len() {
+                    return Err(Error::malformed("Resource data is larger than the file".to_string()));
+                }
+
+                Node::Data(Data {
+                    bytes: exe_buf[data_offset .. data_offset + size].to_vec(),
+                    code_page: pe::read_u32(exe_buf, data_entry + 8),
+                    reserved: pe::read_u32(exe_buf, data_entry + 12),
+                })
+            };
+
+            entries.push(Entry {
+                id,
+                node,
+            });
+        }
+
+        Ok(Directory {
+            characteristics: pe::read_u32(exe_buf, start),
+            time_date_stamp: pe::read_u32(exe_buf, start + 4),
+            major_version: pe::read_u16(exe_buf, start + 8),
+            minor_version: pe::read_u16(exe_buf, start + 10),
+            entries,
+        })
+    }
+
+    /// Serializes the tree to be loaded at `base_rva`. Like resource compilers do, the
+    /// directory tables come first in breadth-first order, then the names, the data entries
+    /// and finally the data itself.
+    pub fn build(&self, base_rva: u32) -> Vec<u8> {
+        let mut directories = vec![self];
+        let mut i = 0;
+        while i < directories.len() {
+            for entry in directories[i].entries.iter() {
+                if let Node::Directory(directory) = &entry.node {
+                    directories.push(directory);
+                }
+            }
+            i += 1;
+        }
+
+        let entries = || directories.iter().flat_map(|directory| directory.entries.iter());
+
+        let mut directory_offsets = Vec::with_capacity(directories.len());
+        let mut offset = 0;
+        for directory in directories.iter() {
+            directory_offsets.push(offset);
+            offset += 16 + directory.entries.len() * 8;
+        }
+
+        let mut name_offsets = Vec::new();
+        for entry in entries() {
+            if let ResourceId::Name(name) = &entry.id {
+                name_offsets.push(offset);
+                offset += 2 + name.len() * 2;
+            }
+        }
+        offset = pe::align_up(offset as u32, 4) as usize;
+
+        let mut data_entry_offsets = Vec::new();
+        for entry in entries() {
+            if let Node::Data(_) = entry.node {
+                data_entry_offsets.push(offset);
+                offset += 16;
+            }
+        }
+
+        let mut data_offsets = Vec::new();
+        for entry in entries() {
+            if let Node::Data(data) = &entry.node {
+                offset = pe::align_up(offset as u32, 8) as usize;
+                data_offsets.push(offset);
+                offset += data.bytes.len();
+            }
+        }
+
+        let mut buf = vec![0; offset];
+        let mut next_directory = 1;
+        let mut next_name = 0;
+        let mut next_data = 0;
+        for (directory, &directory_offset) in directories.iter().zip(directory_offsets.iter()) {
+            let named = directory.entries.iter()
+                .filter(|entry| matches!(entry.id, ResourceId::Name(_)))
+                .count();
+
+            pe::write_u32(&mut buf, directory_offset, directory.characteristics);
+            pe::write_u32(&mut buf, directory_offset + 4, directory.time_date_stamp);
+            pe::write_u16(&mut buf, directory_offset + 8, directory.major_version);
+            pe::write_u16(&mut buf, directory_offset + 10, directory.minor_version);
+            pe::write_u16(&mut buf, directory_offset + 12, named as u16);
+            pe::write_u16(&mut buf, directory_offset + 14, (directory.entries.len() - named) as u16);
+
+            for (j, entry) in directory.entries.iter().enumerate() {
+                let entry_offset = directory_offset + 16 + j * 8;
+
+                let name = match &entry.id {
+                    ResourceId::Id(id) => *id as u32,
+                    ResourceId::Name(name) => {
+                        let name_offset = name_offsets[next_name];
+                        next_name += 1;
+
+                        pe::write_u16(&mut buf, name_offset, name.len() as u16);
+                        for (k, &unit) in name.iter().enumerate() {
+                            pe::write_u16(&mut buf, name_offset + 2 + k * 2, unit);
+                        }
+
+                        name_offset as u32 | 0x8000_0000
+                    },
+                };
+
+                let offset_to_data = match &entry.node {
+                    Node::Directory(_) => {
+                        let subdirectory_offset = directory_offsets[next_directory];
+                        next_directory += 1;
+
+                        subdirectory_offset as u32 | 0x8000_0000
+                    },
+                    Node::Data(data) => {
+                        let data_entry_offset = data_entry_offsets[next_data];
+                        let data_offset = data_offsets[next_data];
+                        next_data += 1;
+
+                        pe::write_u32(&mut buf, data_entry_offset, base_rva.wrapping_add(data_offset as u32));
+                        pe::write_u32(&mut buf, data_entry_offset + 4, data.bytes.len() as u32);
+                        pe::write_u32(&mut buf, data_entry_offset + 8, data.code_page);
+                        pe::write_u32(&mut buf, data_entry_offset + 12, data.reserved);
+                        buf[data_offset .. data_offset + data.bytes.len()].copy_from_slice(&data.bytes);
+
+                        data_entry_offset as u32
+                    },
+                };
+
+                pe::write_u32(&mut buf, entry_offset, name);
+                pe::write_u32(&mut buf, entry_offset + 4, offset_to_data);
+            }
+        }
+
+        buf
+    }
+
+    /// Calls `f` on the data of every resource of the given type.
+    pub fn for_each_of_type<F>(&mut self, resource_type: u16, mut f: F)
+    where
+        F: FnMut(&mut Data),
+    {
+        for entry in self.entries.iter_mut() {
+            if entry.id != ResourceId::Id(resource_type) {
+                continue;
+            }
+
+            if let Node::Directory(directory) = &mut entry.node {
+                directory.for_each_data(&mut f);
+            }
+        }
+    }
+
+    fn for_each_data<F>(&mut self, f: &mut F)
+    where
+        F: FnMut(&mut Data),
+    {
+        for entry in self.entries.iter_mut() {
+            match &mut entry.node {
+                Node::Directory(directory) => directory.for_each_data(f),
+                Node::Data(data) => f(data),
+            }
+        }
+    }
+}
+
+/// A field that is either a null-terminated string or an ordinal, as found in dialog and menu
+/// templates. An empty string is stored as a single null code unit.
+pub enum SzOrOrd {
+    Ord(u16),
+    Sz(Vec<u16>),
+}
+
+/// Cursor over resource data. Reads return `None` once the data is exhausted.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pub offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader {
+            bytes,
+            offset: 0,
+        }
+    }
+
+    pub fn read_bytes(&mut self, size: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.offset .. self.offset.checked_add(size)?)?;
+        self.offset += size;
+
+        Some(bytes)
+    }
+
+    pub fn read_u16(&mut self) -> Option<u16> {
+        self.read_bytes(2).map(|bytes| pe::read_u16(bytes, 0))
+    }
+
+    pub fn read_sz(&mut self) -> Option<Vec<u16>> {
+        let mut units = Vec::new();
+
+        loop {
+            match self.read_u16()? {
+                0 => return Some(units),
+                unit => units.push(unit),
+            }
+        }
+    }
+
+    pub fn read_sz_or_ord(&mut self) -> Option<SzOrOrd> {
+        match self.read_u16()? {
+            0xffff => Some(SzOrOrd::Ord(self.read_u16()?)),
+            0 => Some(SzOrOrd::Sz(Vec::new())),
+            first => {
+                let mut units = vec![first];
+                units.extend(self.read_sz()?);
+
+                Some(SzOrOrd::Sz(units))
+            },
+        }
+    }
+
+    /// Skips padding so that the next read happens on a multiple of `alignment`, or at the
+    /// end of the data if it ends before that.
+    pub fn align(&mut self, alignment: usize) {
+        self.offset = std::cmp::min(pe::align_up(self.offset as u32, alignment as u32) as usize, self.bytes.len());
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.bytes[self.offset ..]
+    }
+}
+
+pub fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_sz(buf: &mut Vec<u8>, units: &[u16]) {
+    for &unit in units.iter() {
+        write_u16(buf, unit);
+    }
+    write_u16(buf, 0);
+}
+
+pub fn write_sz_or_ord(buf: &mut Vec<u8>, field: &SzOrOrd) {
+    match field {
+        SzOrOrd::Ord(ordinal) => {
+            write_u16(buf, 0xffff);
+            write_u16(buf, *ordinal);
+        },
+        SzOrOrd::Sz(units) => write_sz(buf, units),
+    }
+}
+
+pub fn pad(buf: &mut Vec<u8>, alignment: usize) {
+    let aligned = pe::align_up(buf.len() as u32, alignment as u32) as usize;
+    buf.resize(aligned, 0);
+}
+
+/// Replaces `units` with its translation, if there is one. Returns whether it was replaced.
+pub fn translate_units(units: &mut Vec<u16>, lookup: &Lookup) -> bool {
+    let translated = match String::from_utf16(units).ok().and_then(|original| lookup.get(&original)) {
+        Some(translated) => translated,
+        None => return false,
+    };
+
+    *units = translated.encode_utf16().collect();
+
+    true
+}
+
+fn check_bounds(buf: &[u8], offset: usize, size: usize) -> Result<()> {
+    if offset.checked_add(size).is_none_or(|end| end > buf.len()) {
+        Err(Error::out_of_bounds("Resource data", offset, size))
+    } else {
+        Ok(())
+    }
+}
+
+/// Translates the resources of the executable, and writes them back in place if they still
+/// fit or in a new section otherwise.
+pub fn translate_resources(exe_buf: &mut Vec<u8>, translations: &[Translation], warnings: &mut Vec<String>) -> Result<ResourceCounts> {
+    let mut image = Image::parse(exe_buf)?;
+
+    let (rva, size) = match image.data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_RESOURCE) {
+        Some(directory) => directory,
+        None => {
+            warnings.push("The executable has no resources".to_string());
+            return Ok(ResourceCounts::default());
+        },
+    };
+    let base = image.rva_to_offset(rva)
+        .ok_or_else(|| Error::malformed(format!("Resource directory at RVA {:#x} isn't in any section", rva)))?;
+
+    let mut root = Directory::parse(exe_buf, &image, base)?;
+    let section = image.section_name_at(rva).unwrap_or_default();
+    let lookup = Lookup::new(translations, &section);
+
+    let mut counts = ResourceCounts::default();
+    root.for_each_of_type(RT_STRING, |data| {
+        counts.string_tables += string_table::translate(data, &lookup, warnings);
+    });
+    root.for_each_of_type(RT_DIALOG, |data| {
+        counts.dialogs += dialog::translate(data, &lookup, warnings);
+    });
+    root.for_each_of_type(RT_MENU, |data| {
+        counts.menus += menu::translate(data, &lookup, warnings);
+    });
+
+    if counts.total() == 0 {
+        return Ok(counts);
+    }
+    counts.translations = lookup.replaced();
+
+    let rebuilt = root.build(rva);
+    if rebuilt.len() <= size as usize && base + size as usize <= exe_buf.len() {
+        exe_buf[base .. base + size as usize].iter_mut().for_each(|byte| *byte = 0);
+        exe_buf[base .. base + rebuilt.len()].copy_from_slice(&rebuilt);
+        image.set_data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_RESOURCE, rva, rebuilt.len() as u32);
+    } else {
+        let new_rva = image.next_section_rva(exe_buf)?;
+        let rebuilt = root.build(new_rva);
+        image.append_section(exe_buf, SECTION_NAME, &rebuilt, IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ, warnings)?;
+        image.set_data_directory(exe_buf, IMAGE_DIRECTORY_ENTRY_RESOURCE, new_rva, rebuilt.len() as u32);
+        counts.moved_to = Some(SECTION_NAME);
+    }
+
+    Ok(counts)
+}
@@ -0,0 +1,58 @@
+use super::{Data, Lookup, Reader};
+use crate::pe;
+
+/// A string table block holds 16 strings, each prefixed by its length in UTF-16 code units.
+const STRINGS_PER_BLOCK: usize = 16;
+
+/// Translates the strings of an RT_STRING block. Returns the number of strings replaced.
+pub fn translate(data: &mut Data, lookup: &Lookup, warnings: &mut Vec<String>) -> usize {
+    let mut reader = Reader::new(&data.bytes);
+
+    let mut strings: Vec<Vec<u16>> = Vec::with_capacity(STRINGS_PER_BLOCK);
+    for _ in 0 .. STRINGS_PER_BLOCK {
+        let string = match reader.read_u16().and_then(|length| reader.read_bytes(length as usize * 2)) {
+            Some(string) => string,
+            None => {
+                warnings.push("Truncated string table, skipping it".to_string());
+                return 0;
+            },
+        };
+
+        strings.push(string.chunks_exact(2).map(|unit| pe::read_u16(unit, 0)).collect());
+    }
+    let trailing = reader.remaining().to_vec();
+
+    let mut replaced = 0;
+    for string in strings.iter_mut() {
+        let original = string.clone();
+        if !super::translate_units(string, lookup) {
+            continue;
+        }
+
+        if string.len() > u16::MAX as usize {
+            warnings.push(format!("{} is too long for a string table. Skipping this translation.", String::from_utf16_lossy(string)));
+            lookup.restore(&String::from_utf16_lossy(&original));
+            *string = original;
+            continue;
+        }
+
+        replaced += 1;
+    }
+
+    if replaced == 0 {
+        return 0;
+    }
+
+    let mut rebuilt = Vec::with_capacity(data.bytes.len());
+    for string in strings.iter() {
+        super::write_u16(&mut rebuilt, string.len() as u16);
+        for &unit in string.iter() {
+            super::write_u16(&mut rebuilt, unit);
+        }
+    }
+    rebuilt.extend_from_slice(&trailing);
+
+    data.bytes = rebuilt;
+
+    replaced
+}
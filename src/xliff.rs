@@ -0,0 +1,265 @@
+//! XLIFF 1.2 and 2.0 files, as delivered by localization vendors. Units whose state says the
+//! translation isn't ready are skipped.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use roxmltree::Node;
+
+use crate::encoding::Encoding;
+use crate::extract::{self, ExtractedString};
+use crate::{Translation, TranslationSet};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Version {
+    V1_2,
+    V2_0,
+}
+
+fn line(node: Node) -> u32 {
+    node.document().text_pos_at(node.range().start).row
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.tag_name().name() == name)
+}
+
+/// The text of an element, including the text of its inline elements
+fn text(node: Node) -> String {
+    node.descendants()
+        .filter(|descendant| descendant.is_text())
+        .filter_map(|descendant| descendant.text())
+        .collect()
+}
+
+/// Units are recognized by their name only, since tools don't always get the namespace right
+fn units<'a, 'input: 'a>(root: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    root.descendants()
+        .filter(move |node| node.is_element() && node.tag_name().name() == name)
+        .filter(|unit| unit.attribute("translate") != Some("no"))
+}
+
+/// Trans-units of XLIFF 1.2. Targets in the `new` or `needs-*` states are skipped, as are those
+/// without a state in units that aren't approved.
+fn read_1_2(root: Node, translations: &mut Vec<Translation>, rejected: &mut Vec<String>) {
+    for unit in units(root, "trans-unit") {
+        let id = unit.attribute("id").unwrap_or("");
+
+        let source = if let Some(source) = child(unit, "source") {
+            text(source)
+        } else {
+            rejected.push(format!("Unit {} line {} has no source", id, line(unit)));
+            continue;
+        };
+
+        let target = match child(unit, "target") {
+            Some(target) => target,
+            None => continue,
+        };
+
+        let ready = match target.attribute("state") {
+            Some(state) => state != "new" && !state.starts_with("needs-"),
+            None => unit.attribute("approved") != Some("no"),
+        };
+        if !ready {
+            rejected.push(format!("Skipping unit {} line {}, its translation isn't approved: {:?}", id, line(unit), source));
+            continue;
+        }
+
+        let translated = text(target);
+        if !translated.is_empty() {
+            translations.push(Translation {
+                original: source,
+                translated,
+                encoding: None,
+                section: None,
+            });
+        }
+    }
+}
+
+/// Units of XLIFF 2.0, made of the text of all their segments. Units with a segment in the
+/// `initial` state are skipped.
+fn read_2_0(root: Node, translations: &mut Vec<Translation>, rejected: &mut Vec<String>) {
+    for unit in units(root, "unit") {
+        let id = unit.attribute("id").unwrap_or("");
+        let mut original = String::new();
+        let mut translated = String::new();
+        let mut untranslated = false;
+        let mut ready = true;
+
+        let parts = unit.children()
+            .filter(|node| node.is_element() && (node.tag_name().name() == "segment" || node.tag_name().name() == "ignorable"));
+        for part in parts {
+            let source = if let Some(source) = child(part, "source") {
+                text(source)
+            } else {
+                rejected.push(format!("Unit {} line {} has a segment without source", id, line(part)));
+                ready = false;
+                break;
+            };
+
+            match child(part, "target") {
+                Some(target) => translated.push_str(&text(target)),
+                // Ignorable parts, usually whitespace, don't need to be translated
+                None if part.tag_name().name() == "ignorable" => translated.push_str(&source),
+                None => untranslated = true,
+            }
+            original.push_str(&source);
+
+            if part.attribute("state") == Some("initial") {
+                ready = false;
+            }
+        }
+
+        if untranslated || translated.is_empty() {
+            continue;
+        }
+        if !ready {
+            rejected.push(format!("Skipping unit {} line {}, its translation isn't approved: {:?}", id, line(unit), original));
+            continue;
+        }
+
+        translations.push(Translation {
+            original,
+            translated,
+            encoding: None,
+            section: None,
+        });
+    }
+}
+
+/// Reads the translated units of an XLIFF file. Untranslated units are left out silently,
+/// unapproved units and those that can't be read are recorded in `rejected`. Fails if the
+/// file isn't XLIFF 1.2 or 2.0.
+pub fn read(contents: &str) -> Result<TranslationSet, String> {
+    let document = roxmltree::Document::parse(contents).map_err(|error| error.to_string())?;
+    let root = document.root_element();
+
+    if root.tag_name().name() != "xliff" {
+        return Err("Not an XLIFF file".to_string());
+    }
+
+    let mut translations = Vec::new();
+    let mut rejected = Vec::new();
+    match root.attribute("version") {
+        Some(version) if version.starts_with("1.") => read_1_2(root, &mut translations, &mut rejected),
+        Some(version) if version.starts_with("2.") => read_2_0(root, &mut translations, &mut rejected),
+        Some(version) => return Err(format!("XLIFF version {} isn't supported", version)),
+        None => return Err("The XLIFF version is missing".to_string()),
+    }
+
+    Ok(TranslationSet {
+        translations,
+        rejected,
+    })
+}
+
+/// Escapes the markup characters. Those XML 1.0 doesn't allow at all, which section names decoded
+/// from arbitrary bytes can hold, are replaced with U+FFFD.
+fn escape(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+
+    for c in string.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // Line breaks would be normalized away in attributes, and carriage returns anywhere
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            '\t' => escaped.push(c),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => escaped.push(char::REPLACEMENT_CHARACTER),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn location(string: &ExtractedString) -> String {
+    match &string.architecture {
+        Some(architecture) => format!("{} ({}) offset {:#x} address {:#x}", string.section, architecture, string.offset, string.address),
+        None => format!("{} offset {:#x} address {:#x}", string.section, string.offset, string.address),
+    }
+}
+
+/// The size restriction profile measuring bytes in `encoding`, if XLIFF 2.0 has one
+fn storage_profile(encoding: Encoding) -> Option<&'static str> {
+    match encoding {
+        Encoding::Utf8 => Some("xliff:utf8"),
+        Encoding::Utf16Le => Some("xliff:utf16"),
+        _ => None,
+    }
+}
+
+/// Writes the strings as an XLIFF file without targets, with a unit per text as grouped by
+/// `extract::group_by_text`. Notes give the location of each occurrence and the number of bytes
+/// a translation can take. `original` names the executable and `language` is the language of
+/// the strings. Returns the number of units written.
+pub fn write_template(xliff_path: &str, original: &str, language: &str, version: Version, strings: &[ExtractedString]) -> io::Result<usize> {
+    let fd = File::create(xliff_path)?;
+    let mut writer = BufWriter::new(fd);
+
+    let texts = extract::group_by_text(strings);
+    let profile = strings.first().and_then(|string| storage_profile(string.encoding));
+
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    match version {
+        Version::V1_2 => {
+            writeln!(writer, "<xliff version=\"1.2\" xmlns=\"urn:oasis:names:tc:xliff:document:1.2\">")?;
+            writeln!(writer, "  <file original=\"{}\" source-language=\"{}\" datatype=\"plaintext\">", escape(original), escape(language))?;
+            writeln!(writer, "    <body>")?;
+        },
+        Version::V2_0 => {
+            writeln!(writer, "<xliff version=\"2.0\" xmlns=\"urn:oasis:names:tc:xliff:document:2.0\" xmlns:slr=\"urn:oasis:names:tc:xliff:sizerestriction:2.0\" srcLang=\"{}\">", escape(language))?;
+            writeln!(writer, "  <file id=\"f1\" original=\"{}\">", escape(original))?;
+            if let Some(profile) = profile {
+                writeln!(writer, "    <slr:profiles storageProfile=\"{}\"/>", profile)?;
+            }
+        },
+    }
+
+    for (i, (text, occurrences)) in texts.iter().enumerate() {
+        let size = occurrences.iter().map(|string| string.size).min().unwrap_or(0);
+
+        match version {
+            Version::V1_2 => {
+                writeln!(writer, "      <trans-unit id=\"{}\" maxwidth=\"{}\" size-unit=\"byte\" xml:space=\"preserve\">", i + 1, size)?;
+                writeln!(writer, "        <source>{}</source>", escape(text))?;
+                for string in occurrences.iter() {
+                    writeln!(writer, "        <note from=\"translator\">{}</note>", escape(&location(string)))?;
+                }
+                writeln!(writer, "      </trans-unit>")?;
+            },
+            Version::V2_0 => {
+                if profile.is_some() {
+                    writeln!(writer, "    <unit id=\"u{}\" slr:storageRestriction=\"{}\">", i + 1, size)?;
+                } else {
+                    writeln!(writer, "    <unit id=\"u{}\">", i + 1)?;
+                }
+                writeln!(writer, "      <notes>")?;
+                for string in occurrences.iter() {
+                    writeln!(writer, "        <note category=\"location\">{}</note>", escape(&location(string)))?;
+                }
+                writeln!(writer, "        <note category=\"max-bytes\">{} bytes in {}</note>", size, occurrences[0].encoding)?;
+                writeln!(writer, "      </notes>")?;
+                writeln!(writer, "      <segment>")?;
+                writeln!(writer, "        <source xml:space=\"preserve\">{}</source>", escape(text))?;
+                writeln!(writer, "      </segment>")?;
+                writeln!(writer, "    </unit>")?;
+            },
+        }
+    }
+
+    match version {
+        Version::V1_2 => writeln!(writer, "    </body>")?,
+        Version::V2_0 => (),
+    }
+    writeln!(writer, "  </file>")?;
+    writeln!(writer, "</xliff>")?;
+    writer.flush()?;
+
+    Ok(texts.len())
+}